@@ -18,7 +18,7 @@ use crate::{ss58::Ss58AddressFormat, value_error};
 use chainql_core::address as chainql_address;
 
 /// TODO
-#[pyclass(frozen, eq, eq_int, str, hash)]
+#[pyclass(frozen, eq, eq_int, str, hash, module = "chainql.address")]
 #[derive(Clone, Copy, Hash, PartialEq)]
 pub enum SignatureSchema {
     Ed25519,
@@ -0,0 +1,375 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::node::BoxError;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+use tokio::sync::OnceCell;
+
+/// Requests, which are made at the block, and the position of its hash in their parameters.
+///
+/// Responses to them never change once the block is finalized.
+const BLOCK_METHODS: &[(&str, usize)] = &[
+    ("chain_getHeader", 0),
+    ("chain_getBlock", 0),
+    ("state_getRuntimeVersion", 0),
+    ("state_getStorage", 1),
+    ("state_getStorageHash", 1),
+    ("state_getStorageSize", 1),
+    ("state_getKeysPaged", 3),
+    ("state_queryStorageAt", 1),
+    ("childstate_getStorage", 2),
+    ("childstate_getStorageHash", 2),
+    ("childstate_getKeysPaged", 4),
+    ("childstate_getStorageEntries", 2),
+];
+
+/// Requests of the latest state, which last responses are used when the node is unreachable.
+const LATEST_METHODS: &[&str] = &[
+    "chain_getBlockHash",
+    "chain_getFinalizedHead",
+    "chain_getHeader",
+    "state_getRuntimeVersion",
+    "system_chain",
+    "system_properties",
+    "system_version",
+    "rpc_methods",
+];
+
+/// Number of blocks, which responses are kept in memory.
+const MEMORY_BLOCKS: usize = 16;
+
+/// Responses by request method and parameters.
+type Responses = HashMap<(String, String), Value>;
+
+/// Cache directory, where every chain is cached in the subdirectory named by its genesis hash.
+///
+/// `chains.json` maps node URLs to the genesis hashes of their chains,
+/// so that the chain cache is found without the node in the offline mode.
+pub struct CacheDir {
+    dir: PathBuf,
+    /// Whether responses are only served from the cache
+    pub offline: bool,
+    /// Cache of the chain, opened on the first request
+    chain: OnceCell<Arc<Cache>>,
+}
+
+impl CacheDir {
+    pub fn new(dir: PathBuf, offline: bool) -> Self {
+        Self {
+            dir,
+            offline,
+            chain: OnceCell::new(),
+        }
+    }
+
+    /// Cache of the chain at `url`, which genesis hash is requested with `genesis`.
+    ///
+    /// In the offline mode, the genesis hash is the one, seen the last time the node was used.
+    pub async fn chain<F>(&self, url: &str, genesis: F) -> Result<Arc<Cache>, BoxError>
+    where
+        F: Future<Output = Result<String, BoxError>>,
+    {
+        self.chain
+            .get_or_try_init(|| async {
+                let index_path = self.dir.join("chains.json");
+                let mut index: HashMap<String, String> = File::open(&index_path)
+                    .ok()
+                    .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+                    .unwrap_or_default();
+
+                let genesis = if self.offline {
+                    index
+                        .get(url)
+                        .cloned()
+                        .ok_or_else(|| format!("chain of {url} is not cached"))?
+                } else {
+                    let genesis = genesis.await?;
+                    if !is_block_hash(&genesis) {
+                        return Err(format!("invalid genesis hash {genesis}").into());
+                    }
+
+                    if index.get(url) != Some(&genesis) {
+                        index.insert(url.to_owned(), genesis.clone());
+                        std::fs::create_dir_all(&self.dir)?;
+                        replace(&index_path, &json!(index));
+                    }
+                    genesis
+                };
+
+                Ok(Arc::new(Cache::open(self.dir.join(genesis))?))
+            })
+            .await
+            .cloned()
+    }
+}
+
+/// On-disk cache of the node responses of a single chain, which never change.
+///
+/// Layout of the directory:
+/// - `metadata/<spec name>-<spec version>.json` is the runtime metadata,
+/// - `blocks/<hash>.jsonl` are responses to the requests at the finalized block,
+/// - `hashes.jsonl` are hashes of the finalized blocks by their numbers,
+/// - `latest.json` are the last responses about the latest state, e.g. the best block.
+///
+/// Since responses are read without the node, repeated queries
+/// of the finalized blocks work offline.
+pub struct Cache {
+    dir: PathBuf,
+    /// Last responses about the latest state, read on the first use
+    latest: Mutex<Option<Responses>>,
+    /// Responses of the recently used blocks, which were read from the disk
+    blocks: Mutex<VecDeque<(String, Responses)>>,
+    /// Finalized block hashes by number, read on the first use
+    hashes: Mutex<Option<HashMap<u64, String>>>,
+}
+
+impl Cache {
+    fn open(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir.join("metadata"))?;
+        std::fs::create_dir_all(dir.join("blocks"))?;
+
+        Ok(Self {
+            dir,
+            latest: Mutex::default(),
+            blocks: Mutex::default(),
+            hashes: Mutex::default(),
+        })
+    }
+
+    /// Block hash, at which the cacheable request is made.
+    pub fn block_of<'a>(method: &str, params: &'a Value) -> Option<&'a str> {
+        let (_, position) = BLOCK_METHODS.iter().find(|(name, _)| *name == method)?;
+
+        let hash = params.as_array()?.get(*position)?.as_str()?;
+        is_block_hash(hash).then_some(hash)
+    }
+
+    /// Cached response to the request at the finalized block.
+    pub fn response(&self, method: &str, params: &Value) -> Option<Value> {
+        let hash = Self::block_of(method, params)?;
+        let key = (method.to_owned(), params.to_string());
+
+        self.with_block(hash, |responses| responses.get(&key).cloned())
+    }
+
+    /// Save response to the request at the finalized `hash`.
+    pub fn store_response(&self, hash: &str, method: &str, params: &Value, response: &Value) {
+        let key = (method.to_owned(), params.to_string());
+        let entry = json!({ "method": method, "params": params, "response": response });
+
+        self.with_block(hash, |responses| {
+            if responses.insert(key, response.clone()).is_none() {
+                append(
+                    &self.dir.join("blocks").join(format!("{hash}.jsonl")),
+                    &entry,
+                );
+            }
+        })
+    }
+
+    /// Hash of the finalized block with the given number.
+    pub fn block_hash(&self, number: u64) -> Option<String> {
+        self.with_hashes(|hashes| hashes.get(&number).cloned())
+    }
+
+    /// Whether `hash` is known to be finalized.
+    pub fn is_finalized(&self, hash: &str) -> bool {
+        self.with_hashes(|hashes| hashes.values().any(|known| known == hash))
+    }
+
+    pub fn store_block_hash(&self, number: u64, hash: &str) {
+        self.with_hashes(|hashes| {
+            if hashes.insert(number, hash.to_owned()).is_none() {
+                let entry = json!({ "number": number, "hash": hash });
+                append(&self.dir.join("hashes.jsonl"), &entry);
+            }
+        })
+    }
+
+    /// Last response to the request of the latest state, used when the node is unreachable.
+    pub fn latest(&self, method: &str, params: &Value) -> Option<Value> {
+        let key = (method.to_owned(), params.to_string());
+        self.with_latest(|latest| latest.get(&key).cloned())
+    }
+
+    pub fn store_latest(&self, method: &str, params: &Value, response: &Value) {
+        let at_block = params
+            .as_array()
+            .is_some_and(|params| params.iter().any(|param| !param.is_null()));
+        if !LATEST_METHODS.contains(&method) || at_block {
+            return;
+        }
+
+        let key = (method.to_owned(), params.to_string());
+        self.with_latest(|latest| {
+            if latest.get(&key) == Some(response) {
+                return;
+            }
+            latest.insert(key, response.clone());
+
+            let entries: Vec<_> = latest
+                .iter()
+                .map(|((method, params), response)| {
+                    let params: Value = serde_json::from_str(params).unwrap_or(Value::Null);
+                    json!({ "method": method, "params": params, "response": response })
+                })
+                .collect();
+            replace(&self.dir.join("latest.json"), &Value::Array(entries));
+        })
+    }
+
+    /// Runtime metadata of the spec `version`, as returned by `state_getRuntimeVersion`.
+    pub fn metadata(&self, version: &Value) -> Option<Value> {
+        let file = File::open(self.metadata_path(version)?).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+
+    pub fn store_metadata(&self, version: &Value, response: &Value) {
+        let Some(path) = self.metadata_path(version) else {
+            return;
+        };
+
+        replace(&path, response);
+    }
+
+    fn metadata_path(&self, version: &Value) -> Option<PathBuf> {
+        let name = version.get("specName")?.as_str()?;
+        let number = version.get("specVersion")?.as_u64()?;
+
+        let name: String = name
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+            .collect();
+
+        Some(
+            self.dir
+                .join("metadata")
+                .join(format!("{name}-{number}.json")),
+        )
+    }
+
+    fn with_latest<T>(&self, f: impl FnOnce(&mut Responses) -> T) -> T {
+        let mut latest = lock(&self.latest);
+        let latest = latest.get_or_insert_with(|| {
+            let Ok(file) = File::open(self.dir.join("latest.json")) else {
+                return Responses::new();
+            };
+            let entries: Vec<Value> =
+                serde_json::from_reader(BufReader::new(file)).unwrap_or_default();
+            entries.into_iter().filter_map(response_entry).collect()
+        });
+
+        f(latest)
+    }
+
+    fn with_hashes<T>(&self, f: impl FnOnce(&mut HashMap<u64, String>) -> T) -> T {
+        let mut hashes = lock(&self.hashes);
+        let hashes = hashes.get_or_insert_with(|| {
+            read_lines(&self.dir.join("hashes.jsonl"))
+                .into_iter()
+                .filter_map(|entry| {
+                    let number = entry.get("number")?.as_u64()?;
+                    let hash = entry.get("hash")?.as_str()?.to_owned();
+                    Some((number, hash))
+                })
+                .collect()
+        });
+
+        f(hashes)
+    }
+
+    /// Responses of the block, only the recently used blocks are kept in memory.
+    fn with_block<T>(&self, hash: &str, f: impl FnOnce(&mut Responses) -> T) -> T {
+        let mut blocks = lock(&self.blocks);
+
+        let responses = match blocks.iter().position(|(known, _)| known == hash) {
+            Some(idx) => blocks.remove(idx).expect("block should be present"),
+            None => (hash.to_owned(), self.read_block(hash)),
+        };
+        if blocks.len() == MEMORY_BLOCKS {
+            blocks.pop_front();
+        }
+        blocks.push_back(responses);
+
+        let (_, responses) = blocks.back_mut().expect("block was just pushed");
+        f(responses)
+    }
+
+    fn read_block(&self, hash: &str) -> Responses {
+        read_lines(&self.dir.join("blocks").join(format!("{hash}.jsonl")))
+            .into_iter()
+            .filter_map(response_entry)
+            .collect()
+    }
+}
+
+/// Write the whole file through a temporary one, so that partially written file is never read.
+fn replace(path: &Path, value: &Value) {
+    let tmp = path.with_extension("tmp");
+    let written =
+        std::fs::write(&tmp, value.to_string()).and_then(|()| std::fs::rename(&tmp, path));
+
+    if let Err(err) = written {
+        log::warn!("failed to write cache {}: {err}", path.display());
+    }
+}
+
+fn append(path: &Path, entry: &Value) {
+    let written = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{entry}"));
+
+    if let Err(err) = written {
+        log::warn!("failed to write cache {}: {err}", path.display());
+    }
+}
+
+/// Request and its response, saved as `{"method": ..., "params": ..., "response": ...}`.
+fn response_entry(mut entry: Value) -> Option<((String, String), Value)> {
+    let method = entry.get("method")?.as_str()?.to_owned();
+    let params = entry.get("params")?.to_string();
+    Some(((method, params), entry["response"].take()))
+}
+
+/// Entries of the JSON lines file, missing file and malformed lines are skipped.
+fn read_lines(path: &Path) -> Vec<Value> {
+    let Ok(file) = File::open(path) else {
+        return Vec::new();
+    };
+
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect()
+}
+
+/// Whether the parameter is a block hash, not a storage key or a number.
+fn is_block_hash(hash: &str) -> bool {
+    hash.len() == 66 && hash.starts_with("0x") && hash[2..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
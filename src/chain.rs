@@ -12,15 +12,41 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::{jsonnet::JsonnetObject, jsonnet_tokio::execute_jsonnet, utils::jsonnet_error};
+use crate::{
+    cache::CacheDir,
+    child_trie::{child_id, ChildTries, DEFAULT_PAGE_SIZE},
+    diff::{changes_to_py, diff_entries},
+    errors::{evaluation_error, metadata_decode_error, storage_decode_error},
+    hex_bytes::HexBytes,
+    jsonnet::JsonnetObject,
+    jsonnet_tokio::{block_on, cancellable, execute_jsonnet, spawn_jsonnet},
+    node::{Limits, Upstream},
+    raw_state::{self, RawState, RawStorage},
+    replay::{self, BlockPin, Endpoint, Transport},
+    rpc::{paged, Header, Rpc},
+    utils::{chainql_error, jsonnet_error, type_error, value_error},
+    value_type::{storage_entries, StorageEntry},
+};
 use chainql_core::hex::Hex;
 use either::Either;
-use pyo3::{exceptions::PyBaseException, prelude::*};
-use std::collections::BTreeMap;
+use futures_util::future::try_join_all;
+use jrsonnet_evaluator::Val;
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{IntoPyDict, PyBytes, PyDict, PyMapping, PyType},
+};
+use pyo3_async_runtimes::tokio::future_into_py;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
+use tokio::sync::Notify;
 
 /// Selection of optional flags for chain data processing
-#[pyclass(str)]
-#[derive(Clone, Copy, Default)]
+#[pyclass(str, module = "chainql")]
+#[derive(Clone, Default)]
 pub struct ChainOpts {
     /// Whether or not to ignore trie prefixes with no keys
     #[pyo3(get, set)]
@@ -29,17 +55,126 @@ pub struct ChainOpts {
     /// Should default values be included in output
     #[pyo3(get, set)]
     pub include_defaults: bool,
+
+    /// Maximum number of keys, requested from the node by a single `state_getKeysPaged` call
+    ///
+    /// Larger requests of chainql-core are split into pages, smaller ones are sent as is.
+    #[pyo3(get, set)]
+    pub key_page_size: Option<u32>,
+
+    /// Maximum number of RPC requests to the node in flight
+    #[pyo3(get, set)]
+    pub max_parallel_requests: Option<usize>,
+
+    /// Maximum number of storage keys, requested from the node by a single `state_queryStorageAt` call
+    #[pyo3(get, set)]
+    pub request_batch_size: Option<usize>,
+
+    /// Whether values of the storage maps are fetched together with their keys
+    ///
+    /// Otherwise values are fetched when they are read, unless chainql-core preloads them.
+    #[pyo3(get, set)]
+    pub preload: bool,
+
+    /// Directory for runtime metadata and finalized storage, fetched from the node
+    ///
+    /// Every chain is cached in the subdirectory, named by its genesis hash.
+    #[pyo3(get, set)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Whether queries are only served from `cache_dir`, without connecting to the node
+    ///
+    /// Latest and finalized blocks are the last ones, seen while the node was used.
+    #[pyo3(get, set)]
+    pub offline: bool,
 }
 
 #[pymethods]
 impl ChainOpts {
     #[new]
-    #[pyo3(signature = (omit_empty=false, include_defaults=false))]
-    pub fn new(omit_empty: bool, include_defaults: bool) -> Self {
-        ChainOpts {
+    #[pyo3(signature = (
+        omit_empty=false,
+        include_defaults=false,
+        key_page_size=None,
+        max_parallel_requests=None,
+        request_batch_size=None,
+        preload=false,
+        cache_dir=None,
+        offline=false,
+    ))]
+    pub fn new(
+        omit_empty: bool,
+        include_defaults: bool,
+        key_page_size: Option<u32>,
+        max_parallel_requests: Option<usize>,
+        request_batch_size: Option<usize>,
+        preload: bool,
+        cache_dir: Option<PathBuf>,
+        offline: bool,
+    ) -> PyResult<Self> {
+        let opts = ChainOpts {
             omit_empty,
             include_defaults,
+            key_page_size,
+            max_parallel_requests,
+            request_batch_size,
+            preload,
+            cache_dir,
+            offline,
+        };
+        opts.validate()?;
+
+        Ok(opts)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn __getnewargs__(
+        &self,
+    ) -> (
+        bool,
+        bool,
+        Option<u32>,
+        Option<usize>,
+        Option<usize>,
+        bool,
+        Option<PathBuf>,
+        bool,
+    ) {
+        (
+            self.omit_empty,
+            self.include_defaults,
+            self.key_page_size,
+            self.max_parallel_requests,
+            self.request_batch_size,
+            self.preload,
+            self.cache_dir.clone(),
+            self.offline,
+        )
+    }
+}
+
+impl ChainOpts {
+    /// Check that the request limits are positive, as zero limits would never finish the request,
+    /// that the key page is accepted by the node, and that the offline mode has the cache.
+    fn validate(&self) -> PyResult<()> {
+        if self.offline && self.cache_dir.is_none() {
+            return Err(PyValueError::new_err("offline mode requires cache_dir"));
         }
+
+        let positive = |name: &str, value: Option<usize>| match value {
+            Some(0) => Err(PyValueError::new_err(format!("{name} should be positive"))),
+            _ => Ok(()),
+        };
+
+        if let Some(size) = self.key_page_size {
+            if size == 0 || size > DEFAULT_PAGE_SIZE {
+                return Err(PyValueError::new_err(format!(
+                    "key_page_size should be between 1 and {DEFAULT_PAGE_SIZE}"
+                )));
+            }
+        }
+        positive("max_parallel_requests", self.max_parallel_requests)?;
+        positive("request_batch_size", self.request_batch_size)
     }
 }
 
@@ -54,14 +189,29 @@ impl std::fmt::Display for ChainOpts {
             }
         }
 
+        fn pyoption(value: Option<impl ToString>) -> String {
+            value.map_or_else(|| "None".to_owned(), |value| value.to_string())
+        }
+
         f.write_fmt(format_args!(
-            "{{'omit_empty': {omit_empty}, 'include_defaults': {include_defaults}}}",
+            "{{'omit_empty': {omit_empty}, 'include_defaults': {include_defaults}, 'key_page_size': {key_page_size}, 'max_parallel_requests': {max_parallel_requests}, 'request_batch_size': {request_batch_size}, 'preload': {preload}, 'cache_dir': {cache_dir}, 'offline': {offline}}}",
             omit_empty = pybool(self.omit_empty),
             include_defaults = pybool(self.include_defaults),
+            key_page_size = pyoption(self.key_page_size),
+            max_parallel_requests = pyoption(self.max_parallel_requests),
+            request_batch_size = pyoption(self.request_batch_size),
+            preload = pybool(self.preload),
+            cache_dir = pyoption(
+                self.cache_dir
+                    .as_ref()
+                    .map(|dir| format!("'{}'", dir.display()))
+            ),
+            offline = pybool(self.offline),
         ))
     }
 }
 
+/// Request limits and preload are not known to chainql-core, they are applied by the local endpoint.
 impl From<ChainOpts> for chainql_core::ChainOpts {
     fn from(opts: ChainOpts) -> Self {
         chainql_core::ChainOpts {
@@ -71,80 +221,1167 @@ impl From<ChainOpts> for chainql_core::ChainOpts {
     }
 }
 
+/// Reference to the block, which state is being read
+#[pyclass(frozen, eq, hash, get_all, str, module = "chainql")]
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct BlockRef {
+    /// Block number
+    pub number: u32,
+
+    /// Block hash
+    pub hash: String,
+
+    /// Hash of the parent block
+    pub parent_hash: String,
+
+    /// Storage trie root after the block execution
+    pub state_root: String,
+
+    /// Block timestamp in milliseconds, if chain has `Timestamp` pallet
+    pub timestamp: Option<u64>,
+}
+
+#[pymethods]
+impl BlockRef {
+    #[new]
+    #[pyo3(signature = (number, hash, parent_hash, state_root, timestamp=None))]
+    fn py_new(
+        number: u32,
+        hash: String,
+        parent_hash: String,
+        state_root: String,
+        timestamp: Option<u64>,
+    ) -> Self {
+        Self {
+            number,
+            hash,
+            parent_hash,
+            state_root,
+            timestamp,
+        }
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn __getnewargs__(&self) -> (u32, String, String, String, Option<u64>) {
+        (
+            self.number,
+            self.hash.clone(),
+            self.parent_hash.clone(),
+            self.state_root.clone(),
+            self.timestamp,
+        )
+    }
+}
+
+impl BlockRef {
+    fn new(hash: String, header: Header) -> PyResult<Self> {
+        Ok(BlockRef {
+            number: header.number()?,
+            hash,
+            parent_hash: header.parent_hash,
+            state_root: header.state_root,
+            timestamp: None,
+        })
+    }
+}
+
+impl std::fmt::Display for BlockRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!(
+            "{{'number': {number}, 'hash': '{hash}', 'parent_hash': '{parent_hash}', 'state_root': '{state_root}', 'timestamp': {timestamp}}}",
+            number = self.number,
+            hash = self.hash,
+            parent_hash = self.parent_hash,
+            state_root = self.state_root,
+            timestamp = self
+                .timestamp
+                .map_or_else(|| "None".to_owned(), |timestamp| timestamp.to_string()),
+        ))
+    }
+}
+
+/// Chain state at the specific block
+#[pyclass(extends = JsonnetObject, module = "chainql")]
+pub struct BlockState {
+    block: BlockRef,
+
+    /// `Timestamp.Now`, read on the first access to the block
+    timestamp: OnceLock<Option<u64>>,
+
+    source: ChainSource,
+    rpc: Rpc,
+}
+
+impl BlockState {
+    fn new(
+        chain: &JsonnetObject,
+        source: ChainSource,
+        rpc: Rpc,
+        pin: &BlockPin,
+        block: BlockRef,
+    ) -> PyResult<Py<Self>> {
+        let state = pin.with(block.number, block.hash.clone(), || {
+            chain_block(chain, block.number)
+        })?;
+
+        let init = PyClassInitializer::from(state).add_subclass(Self {
+            block,
+            timestamp: OnceLock::new(),
+            source,
+            rpc,
+        });
+
+        Python::with_gil(|py| Py::new(py, init))
+    }
+}
+
+#[pymethods]
+impl BlockState {
+    /// States are compared by identity, comparing the whole chain state is too expensive.
+    fn __eq__(slf: &Bound<'_, Self>, other: &Bound<'_, PyAny>) -> bool {
+        slf.is(other)
+    }
+
+    fn __hash__(slf: &Bound<'_, Self>) -> u64 {
+        slf.as_ptr() as u64
+    }
+
+    /// Block, which state is being read
+    #[getter]
+    fn block(slf: PyRef<'_, Self>) -> PyResult<BlockRef> {
+        let timestamp = match slf.timestamp.get() {
+            Some(timestamp) => *timestamp,
+            None => {
+                let state: JsonnetObject = slf.as_ref().clone();
+                let timestamp = execute_jsonnet(slf.py(), move |_| state_timestamp(&state))?;
+                *slf.timestamp.get_or_init(|| timestamp)
+            }
+        };
+
+        Ok(BlockRef {
+            timestamp,
+            ..slf.block.clone()
+        })
+    }
+
+    /// Reconnect to the chain and read state at the pickled block.
+    #[staticmethod]
+    #[pyo3(signature = (url, opts, replay, hash))]
+    fn _restore(
+        py: Python<'_>,
+        url: Option<String>,
+        opts: Option<ChainOpts>,
+        replay: Option<PathBuf>,
+        hash: String,
+    ) -> PyResult<Py<Self>> {
+        let chain = Chain::new(py, url, opts, None, replay)?;
+
+        let rpc = chain.rpc.clone();
+        let block = py.allow_threads(|| block_on(cancellable(block_ref(&rpc, hash))))?;
+
+        chain.state(py, block)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn __reduce__<'py>(
+        &self,
+        py: Python<'py>,
+    ) -> PyResult<(
+        Bound<'py, PyAny>,
+        (Option<String>, Option<ChainOpts>, Option<PathBuf>, String),
+    )> {
+        let restore = py.get_type::<BlockState>().getattr("_restore")?;
+        let (url, opts, replay) = self.source.args();
+
+        Ok((restore, (url, opts, replay, self.block.hash.clone())))
+    }
+
+    fn __copy__(slf: PyRef<'_, Self>) -> PyResult<Py<Self>> {
+        let state: &JsonnetObject = slf.as_ref();
+        let init = PyClassInitializer::from(state.clone()).add_subclass(Self {
+            block: slf.block.clone(),
+            timestamp: slf.timestamp.clone(),
+            source: slf.source.clone(),
+            rpc: slf.rpc.clone(),
+        });
+
+        Py::new(slf.py(), init)
+    }
+
+    fn __deepcopy__(slf: PyRef<'_, Self>, _memo: &Bound<'_, PyAny>) -> PyResult<Py<Self>> {
+        Self::__copy__(slf)
+    }
+
+    /// Default child tries at the block, `state._child[child_key]`
+    #[getter(_child)]
+    fn child_tries(&self) -> ChildTries {
+        let page_size = self
+            .source
+            .opts
+            .as_ref()
+            .and_then(|opts| opts.key_page_size);
+        ChildTries::new(self.rpc.clone(), self.block.hash.clone(), page_size)
+    }
+}
+
+/// Connection parameters, which are enough to connect to the chain again.
+#[derive(Clone)]
+struct ChainSource {
+    /// Node URL, which is not used in the replay mode
+    url: Option<String>,
+    opts: Option<ChainOpts>,
+    transport: Option<Transport>,
+}
+
+impl ChainSource {
+    fn new(
+        url: Option<String>,
+        opts: Option<ChainOpts>,
+        record: Option<PathBuf>,
+        replay: Option<PathBuf>,
+    ) -> PyResult<Self> {
+        let transport = Transport::new(record, replay)?;
+        // Limits may be changed after the options are created.
+        opts.as_ref().map(ChainOpts::validate).transpose()?;
+        if url.is_none() && !matches!(transport, Some(Transport::Replay(_))) {
+            return Err(PyValueError::new_err(
+                "url is required, unless replay is used",
+            ));
+        }
+
+        Ok(Self {
+            url,
+            opts,
+            transport,
+        })
+    }
+
+    /// Start the local endpoint, which is used instead of the node.
+    async fn endpoint(&self, pin: BlockPin) -> PyResult<Endpoint> {
+        let opts = self.opts.clone().unwrap_or_default();
+        let upstream = Upstream {
+            url: self.url.clone().unwrap_or_default(),
+            limits: Limits::new(
+                opts.key_page_size,
+                opts.max_parallel_requests,
+                opts.request_batch_size,
+                opts.preload,
+            ),
+            cache: opts
+                .cache_dir
+                .map(|dir| Arc::new(CacheDir::new(dir, opts.offline))),
+        };
+
+        replay::serve(upstream, self.transport.clone(), pin).await
+    }
+
+    /// Arguments to reconnect after unpickling.
+    ///
+    /// Replay is kept, but recording is not, so that unpickled
+    /// chain doesn't overwrite the recording of the original one.
+    fn args(&self) -> (Option<String>, Option<ChainOpts>, Option<PathBuf>) {
+        let replay = match &self.transport {
+            Some(Transport::Replay(path)) => Some(path.clone()),
+            _ => None,
+        };
+
+        (self.url.clone(), self.opts.clone(), replay)
+    }
+}
+
 /// TODO
-#[pyclass(unsendable)]
-pub struct Chain(JsonnetObject);
+#[pyclass(module = "chainql")]
+#[derive(Clone)]
+pub struct Chain {
+    chain: JsonnetObject,
+    rpc: Rpc,
+    source: ChainSource,
+    pin: BlockPin,
+}
 
 #[pymethods]
 impl Chain {
+    /// Connect to the node at `url`.
+    ///
+    /// With `record`, every RPC request and response is saved to the file.
+    /// With `replay`, responses are served from such file and node is not used,
+    /// so `url` may be omitted.
     #[new]
-    #[pyo3(signature = (url, opts=None))]
-    pub fn new(url: String, opts: Option<ChainOpts>) -> PyResult<Self> {
-        execute_jsonnet(|cancel| {
-            chainql_core::chain(url, opts.map(Into::into), cancel)
-                .map(|chain| Self(JsonnetObject(chain)))
-                .map_err(|err| PyBaseException::new_err(err.to_string()))
+    #[pyo3(signature = (url=None, opts=None, record=None, replay=None))]
+    pub fn new(
+        py: Python<'_>,
+        url: Option<String>,
+        opts: Option<ChainOpts>,
+        record: Option<PathBuf>,
+        replay: Option<PathBuf>,
+    ) -> PyResult<Self> {
+        let source = ChainSource::new(url, opts, record, replay)?;
+
+        let pin = BlockPin::default();
+        let endpoint = py.allow_threads(|| block_on(source.endpoint(pin.clone())))?;
+        let rpc = Rpc::new(&endpoint);
+
+        let opts = source.opts.clone();
+        let chain = execute_jsonnet(py, move |cancel| {
+            connect_chain(endpoint.core_url, opts, cancel)
+        })?;
+
+        Ok(Self {
+            chain,
+            rpc,
+            source,
+            pin,
         })
     }
 
-    pub fn latest(&self) -> PyResult<JsonnetObject> {
-        execute_jsonnet(|_| {
-            let chain = &self.0 .0;
+    /// Pickled chain connects to the same URL with the same options.
+    #[allow(clippy::type_complexity)]
+    fn __reduce__<'py>(
+        &self,
+        py: Python<'py>,
+    ) -> (
+        Bound<'py, PyType>,
+        (
+            Option<String>,
+            Option<ChainOpts>,
+            Option<PathBuf>,
+            Option<PathBuf>,
+        ),
+    ) {
+        let (url, opts, replay) = self.source.args();
+        (py.get_type::<Chain>(), (url, opts, None, replay))
+    }
+
+    fn __copy__(&self) -> Self {
+        self.clone()
+    }
+
+    fn __deepcopy__(&self, _memo: &Bound<'_, PyAny>) -> Self {
+        self.clone()
+    }
 
-            let latest = chain
-                .get("latest".into())
-                .map_err(jsonnet_error)?
-                .expect("`latest` field should be presented")
-                .as_obj()
-                .expect("`latest` field should be an object");
+    pub fn latest(&self, py: Python<'_>) -> PyResult<Py<BlockState>> {
+        let rpc = self.rpc.clone();
+        let block = py.allow_threads(|| block_on(cancellable(latest_block(rpc))))?;
 
-            Ok(JsonnetObject(latest))
+        self.state(py, block)
+    }
+
+    /// State at the last finalized block
+    pub fn finalized(&self, py: Python<'_>) -> PyResult<Py<BlockState>> {
+        let rpc = self.rpc.clone();
+        let block = py.allow_threads(|| block_on(cancellable(finalized_block(rpc))))?;
+
+        self.state(py, block)
+    }
+
+    pub fn block(&self, py: Python<'_>, block: u32) -> PyResult<Py<BlockState>> {
+        let rpc = self.rpc.clone();
+        let block = py.allow_threads(|| block_on(cancellable(numbered_block(rpc, block))))?;
+
+        self.state(py, block)
+    }
+
+    /// State at the block with the given hash, which may be outside of the canonical chain
+    pub fn at_hash(
+        &self,
+        py: Python<'_>,
+        hash: Either<Vec<u8>, String>,
+    ) -> PyResult<Py<BlockState>> {
+        let hash = block_hash(hash)?;
+
+        let rpc = self.rpc.clone();
+        let block = py.allow_threads(|| block_on(cancellable(block_ref(&rpc, hash))))?;
+
+        self.state(py, block)
+    }
+
+    /// Storage changes between two blocks, optionally only in `pallets`
+    ///
+    /// Keys of the pallets are listed at both blocks and their raw values are read with
+    /// `state_queryStorage` over the block range, so unchanged values are fetched once
+    /// and only changed keys are decoded. Result has the same `added`, `removed` and
+    /// `changed` keys as [`JsonnetObject::diff`], but paths are pallet and storage item
+    /// names followed by the storage map keys, and values under them are compared as a whole.
+    #[pyo3(signature = (from_block, to_block, pallets=None))]
+    pub fn diff(
+        &self,
+        py: Python<'_>,
+        from_block: u32,
+        to_block: u32,
+        pallets: Option<Vec<String>>,
+    ) -> PyResult<Py<PyDict>> {
+        let chain = self.chain.clone();
+        let rpc = self.rpc.clone();
+        let opts = self.source.opts.clone();
+
+        py.allow_threads(move || {
+            block_on(cancellable(state_diff(
+                chain, rpc, opts, from_block, to_block, pallets,
+            )))
+        })
+    }
+}
+
+impl Chain {
+    fn state(&self, py: Python<'_>, block: BlockRef) -> PyResult<Py<BlockState>> {
+        let chain = self.chain.clone();
+        let source = self.source.clone();
+        let rpc = self.rpc.clone();
+        let pin = self.pin.clone();
+        execute_jsonnet(py, move |_| {
+            BlockState::new(&chain, source, rpc, &pin, block)
+        })
+    }
+}
+
+/// Same as [`Chain`], but every method returns an awaitable
+///
+/// Storage is read during evaluation on the single evaluator thread,
+/// so awaitables don't block the event loop, but don't run concurrently either.
+#[pyclass(module = "chainql")]
+#[derive(Clone)]
+pub struct AsyncChain {
+    chain: JsonnetObject,
+    rpc: Rpc,
+    source: ChainSource,
+    pin: BlockPin,
+}
+
+#[pymethods]
+impl AsyncChain {
+    /// Connect to the node at `url`, `record` and `replay` are the same as for [`Chain`]
+    #[staticmethod]
+    #[pyo3(signature = (url=None, opts=None, record=None, replay=None))]
+    pub fn connect(
+        py: Python<'_>,
+        url: Option<String>,
+        opts: Option<ChainOpts>,
+        record: Option<PathBuf>,
+        replay: Option<PathBuf>,
+    ) -> PyResult<Bound<'_, PyAny>> {
+        let source = ChainSource::new(url, opts, record, replay)?;
+
+        future_into_py(py, async move {
+            let pin = BlockPin::default();
+            let endpoint = source.endpoint(pin.clone()).await?;
+            let rpc = Rpc::new(&endpoint);
+
+            let opts = source.opts.clone();
+            let chain =
+                spawn_jsonnet(move |cancel| connect_chain(endpoint.core_url, opts, cancel)).await?;
+
+            Ok(Self {
+                chain,
+                rpc,
+                source,
+                pin,
+            })
         })
     }
 
-    pub fn block(&self, block: u32) -> PyResult<JsonnetObject> {
-        execute_jsonnet(|_| {
-            let chain = &self.0 .0;
+    /// Reconnect to the chain, used for unpickling.
+    #[staticmethod]
+    #[pyo3(signature = (url, opts, replay))]
+    fn _restore(
+        py: Python<'_>,
+        url: Option<String>,
+        opts: Option<ChainOpts>,
+        replay: Option<PathBuf>,
+    ) -> PyResult<Self> {
+        let Chain {
+            chain,
+            rpc,
+            source,
+            pin,
+        } = Chain::new(py, url, opts, None, replay)?;
+
+        Ok(Self {
+            chain,
+            rpc,
+            source,
+            pin,
+        })
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn __reduce__<'py>(
+        &self,
+        py: Python<'py>,
+    ) -> PyResult<(
+        Bound<'py, PyAny>,
+        (Option<String>, Option<ChainOpts>, Option<PathBuf>),
+    )> {
+        let restore = py.get_type::<AsyncChain>().getattr("_restore")?;
+        Ok((restore, self.source.args()))
+    }
+
+    fn __copy__(&self) -> Self {
+        self.clone()
+    }
+
+    fn __deepcopy__(&self, _memo: &Bound<'_, PyAny>) -> Self {
+        self.clone()
+    }
+
+    pub fn latest<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let chain = self.chain.clone();
+        let source = self.source.clone();
+        let rpc = self.rpc.clone();
+        let pin = self.pin.clone();
+
+        future_into_py(
+            py,
+            cancellable(async move {
+                let block = latest_block(rpc.clone()).await?;
+                spawn_jsonnet(move |_| BlockState::new(&chain, source, rpc, &pin, block)).await
+            }),
+        )
+    }
+
+    /// State at the last finalized block
+    pub fn finalized<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let chain = self.chain.clone();
+        let source = self.source.clone();
+        let rpc = self.rpc.clone();
+        let pin = self.pin.clone();
+
+        future_into_py(
+            py,
+            cancellable(async move {
+                let block = finalized_block(rpc.clone()).await?;
+                spawn_jsonnet(move |_| BlockState::new(&chain, source, rpc, &pin, block)).await
+            }),
+        )
+    }
+
+    pub fn block<'py>(&self, py: Python<'py>, block: u32) -> PyResult<Bound<'py, PyAny>> {
+        let chain = self.chain.clone();
+        let source = self.source.clone();
+        let rpc = self.rpc.clone();
+        let pin = self.pin.clone();
+
+        future_into_py(
+            py,
+            cancellable(async move {
+                let block = numbered_block(rpc.clone(), block).await?;
+                spawn_jsonnet(move |_| BlockState::new(&chain, source, rpc, &pin, block)).await
+            }),
+        )
+    }
+
+    /// State at the block with the given hash, which may be outside of the canonical chain
+    pub fn at_hash<'py>(
+        &self,
+        py: Python<'py>,
+        hash: Either<Vec<u8>, String>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let hash = block_hash(hash)?;
+        let chain = self.chain.clone();
+        let source = self.source.clone();
+        let rpc = self.rpc.clone();
+        let pin = self.pin.clone();
+
+        future_into_py(
+            py,
+            cancellable(async move {
+                let block = block_ref(&rpc, hash).await?;
+                spawn_jsonnet(move |_| BlockState::new(&chain, source, rpc, &pin, block)).await
+            }),
+        )
+    }
+
+    /// Storage changes between two blocks, same as [`Chain::diff`]
+    #[pyo3(signature = (from_block, to_block, pallets=None))]
+    pub fn diff<'py>(
+        &self,
+        py: Python<'py>,
+        from_block: u32,
+        to_block: u32,
+        pallets: Option<Vec<String>>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        let chain = self.chain.clone();
+        let rpc = self.rpc.clone();
+        let opts = self.source.opts.clone();
+
+        future_into_py(
+            py,
+            cancellable(state_diff(chain, rpc, opts, from_block, to_block, pallets)),
+        )
+    }
+}
+
+/// Normalize block hash, passed as bytes or hex string.
+fn block_hash(hash: Either<Vec<u8>, String>) -> PyResult<String> {
+    let hash = match hash {
+        Either::Left(bytes) => bytes,
+        Either::Right(hex) => chainql_core::hex::from_hex(&hex).map_err(value_error)?,
+    };
+
+    if hash.len() != 32 {
+        return Err(PyValueError::new_err(format!(
+            "block hash should be 32 bytes long, got {}",
+            hash.len()
+        )));
+    }
+
+    Ok(chainql_core::hex::to_hex(&hash))
+}
 
-            let block_func = chain
-                .get("block".into())
-                .map_err(jsonnet_error)?
-                .expect("`block` field should be presented")
-                .as_func()
-                .expect("`block` field should be a function");
+/// Block with the given hash, in the canonical chain or not.
+async fn block_ref(rpc: &Rpc, hash: String) -> PyResult<BlockRef> {
+    let header = rpc
+        .header(&hash)
+        .await?
+        .ok_or_else(|| PyValueError::new_err(format!("unknown block {hash}")))?;
 
-            let block = block_func
-                .evaluate_simple(&(block,), false)
-                .map_err(jsonnet_error)?
-                .as_obj()
-                .expect("`block` should return an object");
+    BlockRef::new(hash, header)
+}
 
-            Ok(JsonnetObject(block))
+/// The best block.
+async fn latest_block(rpc: Rpc) -> PyResult<BlockRef> {
+    let hash = rpc.best_head().await?;
+    block_ref(&rpc, hash).await
+}
+
+/// The last finalized block.
+async fn finalized_block(rpc: Rpc) -> PyResult<BlockRef> {
+    let hash = rpc.finalized_head().await?;
+    block_ref(&rpc, hash).await
+}
+
+/// Block with the given number in the canonical chain.
+async fn numbered_block(rpc: Rpc, number: u32) -> PyResult<BlockRef> {
+    let hash = rpc
+        .block_hash(number)
+        .await?
+        .ok_or_else(|| PyValueError::new_err(format!("unknown block #{number}")))?;
+
+    block_ref(&rpc, hash).await
+}
+
+/// Decoded storage changes between two blocks.
+async fn state_diff(
+    chain: JsonnetObject,
+    rpc: Rpc,
+    opts: Option<ChainOpts>,
+    from_block: u32,
+    to_block: u32,
+    pallets: Option<Vec<String>>,
+) -> PyResult<Py<PyDict>> {
+    let from = numbered_block(rpc.clone(), from_block).await?;
+    let to = numbered_block(rpc.clone(), to_block).await?;
+
+    let (old, new, items) = spawn_jsonnet(move |_| {
+        let old = chain_block(&chain, from_block)?;
+        let new = chain_block(&chain, to_block)?;
+        let items = storage_items(&old, &new, pallets)?;
+        Ok((old, new, items))
+    })
+    .await?;
+
+    let opts = opts.unwrap_or_default();
+    let paging = Paging {
+        page_size: opts.key_page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+        batch_size: opts
+            .request_batch_size
+            .unwrap_or(DEFAULT_PAGE_SIZE as usize),
+    };
+
+    // Pallets are read concurrently, requests in flight are limited by the local endpoint.
+    let prefixes = items.pallet_prefixes();
+    let changes = try_join_all(
+        prefixes
+            .iter()
+            .map(|prefix| changed_values(&rpc, &items, prefix, &from, &to, paging)),
+    )
+    .await?;
+
+    let mut old_storage = RawStorage::new();
+    let mut new_storage = RawStorage::new();
+    for (key, (old_value, new_value)) in changes.into_iter().flatten() {
+        let key = raw_hex(&key)?;
+        if let Some(value) = old_value {
+            old_storage.insert(key.clone(), raw_hex(&value)?);
+        }
+        if let Some(value) = new_value {
+            new_storage.insert(key, raw_hex(&value)?);
+        }
+    }
+
+    spawn_jsonnet(move |_| {
+        let old = decode_storage(&old, old_storage)?;
+        let new = decode_storage(&new, new_storage)?;
+
+        let mut changes = Vec::new();
+        for item in items.entries {
+            let old_value = storage_item(&old, &item.pallet, &item.item)?;
+            let new_value = storage_item(&new, &item.pallet, &item.item)?;
+
+            let mut path = vec![item.pallet, item.item];
+            diff_entries(old_value, new_value, item.keys, &mut path, &mut changes)?;
+        }
+
+        Python::with_gil(|py| changes_to_py(py, changes).map(Bound::unbind))
+    })
+    .await
+}
+
+/// Sizes of the key pages and value batches, requested by the diff.
+#[derive(Clone, Copy)]
+struct Paging {
+    page_size: u32,
+    batch_size: usize,
+}
+
+/// Raw values of the pallet storage items, which differ between the blocks, as `(old, new)`.
+async fn changed_values(
+    rpc: &Rpc,
+    items: &StorageItems,
+    prefix: &str,
+    from: &BlockRef,
+    to: &BlockRef,
+    paging: Paging,
+) -> PyResult<Vec<(String, (Option<String>, Option<String>))>> {
+    let (old_keys, new_keys) = tokio::try_join!(
+        keys_at(rpc, prefix, &from.hash, paging.page_size),
+        keys_at(rpc, prefix, &to.hash, paging.page_size)
+    )?;
+
+    let keys: BTreeSet<String> = old_keys.into_iter().chain(new_keys).collect();
+    let keys: Vec<String> = keys
+        .into_iter()
+        .filter(|key| items.item_of(key).is_some())
+        .collect();
+
+    // Range is queried from the older block, so values are swapped back for the reversed diff.
+    let reversed = from.number > to.number;
+    let (first, last) = if reversed { (to, from) } else { (from, to) };
+
+    let values = try_join_all(
+        keys.chunks(paging.batch_size)
+            .map(|keys| rpc.storage_range(keys, &first.hash, &last.hash)),
+    )
+    .await?;
+
+    Ok(values
+        .into_iter()
+        .flatten()
+        .map(|(key, (first, last))| {
+            if reversed {
+                (key, (last, first))
+            } else {
+                (key, (first, last))
+            }
+        })
+        .filter(|(_, (old, new))| old != new)
+        .collect())
+}
+
+/// Storage items, which are compared by the diff.
+struct StorageItems {
+    entries: Vec<StorageEntry>,
+    /// Raw key prefixes of the items, as `(pallet prefix, item prefix)`
+    prefixes: Vec<(String, String)>,
+}
+
+impl StorageItems {
+    fn new(entries: Vec<StorageEntry>) -> Self {
+        let prefixes = entries
+            .iter()
+            .map(|entry| (entry.pallet_prefix(), entry.key_prefix()))
+            .collect();
+
+        Self { entries, prefixes }
+    }
+
+    /// Raw key prefixes of the pallets, without duplicates.
+    fn pallet_prefixes(&self) -> BTreeSet<String> {
+        self.prefixes
+            .iter()
+            .map(|(pallet, _)| pallet.clone())
+            .collect()
+    }
+
+    /// Storage item, which raw key belongs to.
+    ///
+    /// Keys, which are not described by the metadata, e.g. pallet storage versions, have no item.
+    fn item_of(&self, key: &str) -> Option<&StorageEntry> {
+        let idx = self
+            .prefixes
+            .iter()
+            .position(|(_, item)| key.starts_with(item.as_str()))?;
+        Some(&self.entries[idx])
+    }
+}
+
+/// Storage items of the `pallets`, or of every pallet, present at either block.
+///
+/// Items are described by the metadata of the newer block, if they are present there.
+fn storage_items(
+    old: &JsonnetObject,
+    new: &JsonnetObject,
+    pallets: Option<Vec<String>>,
+) -> PyResult<StorageItems> {
+    let entries = |state: &JsonnetObject| {
+        storage_entries(&state.0).ok_or_else(|| {
+            metadata_decode_error("runtime metadata doesn't describe storage items".into())
         })
+    };
+
+    let mut items = entries(new)?;
+    for entry in entries(old)? {
+        let known = items
+            .iter()
+            .any(|item| item.pallet == entry.pallet && item.item == entry.item);
+        if !known {
+            items.push(entry);
+        }
+    }
+
+    if let Some(pallets) = pallets {
+        if let Some(pallet) = pallets
+            .iter()
+            .find(|pallet| !items.iter().any(|item| &item.pallet == *pallet))
+        {
+            return Err(PyValueError::new_err(format!("unknown pallet {pallet}")));
+        }
+
+        items.retain(|item| pallets.contains(&item.pallet));
+    }
+
+    Ok(StorageItems::new(items))
+}
+
+/// Decode the raw storage entries with the metadata of the block state.
+fn decode_storage(state: &JsonnetObject, storage: RawStorage) -> PyResult<JsonnetObject> {
+    let meta = state
+        .0
+        .get("_meta".into())
+        .map_err(jsonnet_error)?
+        .and_then(|meta| meta.as_obj())
+        .ok_or_else(|| metadata_decode_error("block state has no metadata".into()))?;
+
+    let opts = ChainOpts {
+        omit_empty: true,
+        ..ChainOpts::default()
+    };
+
+    dump_storage(Either::Left(JsonnetObject::new(meta)), storage, Some(opts))
+}
+
+fn storage_item(state: &JsonnetObject, pallet: &str, item: &str) -> PyResult<Option<Val>> {
+    let origin = state.1.join(Either::Left(pallet.to_owned()));
+    let pallet = state
+        .0
+        .get(pallet.into())
+        .map_err(|err| origin.error(err))?;
+    let Some(pallet) = pallet.and_then(|pallet| pallet.as_obj()) else {
+        return Ok(None);
+    };
+
+    let origin = origin.join(Either::Left(item.to_owned()));
+    pallet.get(item.into()).map_err(|err| origin.error(err))
+}
+
+/// Every key with the `prefix` at the block.
+async fn keys_at(rpc: &Rpc, prefix: &str, hash: &str, page_size: u32) -> PyResult<Vec<String>> {
+    paged(page_size, |start| async move {
+        rpc.keys_paged(prefix, page_size, start.as_deref(), hash)
+            .await
+    })
+    .await
+}
+
+fn raw_hex(hex: &str) -> PyResult<Hex> {
+    chainql_core::hex::from_hex(hex)
+        .map(Hex)
+        .map_err(value_error)
+}
+
+/// Read `Timestamp.Now` from the block state.
+fn state_timestamp(state: &JsonnetObject) -> PyResult<Option<u64>> {
+    let Some(pallet) = state.0.get("Timestamp".into()).map_err(jsonnet_error)? else {
+        return Ok(None);
+    };
+
+    let Some(now) = pallet
+        .as_obj()
+        .map(|pallet| pallet.get("Now".into()))
+        .transpose()
+        .map_err(jsonnet_error)?
+        .flatten()
+    else {
+        return Ok(None);
+    };
+
+    Ok(match now {
+        // Integral numbers are displayed without fraction and exponent, so
+        // fractional, negative and out of range values are rejected by parsing.
+        jrsonnet_evaluator::Val::Num(num) => num.get().to_string().parse().ok(),
+        jrsonnet_evaluator::Val::BigInt(num) => u64::try_from(num.as_ref()).ok(),
+        _ => None,
+    })
+}
+
+/// Connect chainql-core to the local endpoint.
+///
+/// Failures of the node requests are observed by the endpoint and raised as
+/// `ConnectionError` or `RpcError`, the rest are failures of chainql-core itself.
+fn connect_chain(
+    url: String,
+    opts: Option<ChainOpts>,
+    cancel: Arc<Notify>,
+) -> PyResult<JsonnetObject> {
+    chainql_core::chain(url, opts.map(Into::into), cancel)
+        .map(JsonnetObject::new)
+        .map_err(|err| chainql_error(&err, evaluation_error))
+}
+
+fn chain_block(chain: &JsonnetObject, block: u32) -> PyResult<JsonnetObject> {
+    let block_func = chain
+        .0
+        .get("block".into())
+        .map_err(jsonnet_error)?
+        .expect("`block` field should be presented")
+        .as_func()
+        .expect("`block` field should be a function");
+
+    let block = block_func
+        .evaluate_simple(&(block,), false)
+        .map_err(jsonnet_error)?
+        .as_obj()
+        .expect("`block` should return an object");
+
+    Ok(JsonnetObject::storage(block))
+}
+
+/// State, decoded from the raw storage by [`dump`]
+#[pyclass(extends = JsonnetObject, module = "chainql")]
+pub struct DumpState {
+    children: Arc<BTreeMap<Hex, RawStorage>>,
+}
+
+#[pymethods]
+impl DumpState {
+    /// Default child tries of the dump, `state._child[child_key]`
+    #[getter(_child)]
+    fn child_tries(&self) -> ChildTries {
+        ChildTries::dump(Arc::clone(&self.children))
+    }
+
+    #[staticmethod]
+    fn _restore(
+        py: Python<'_>,
+        value: Py<PyDict>,
+        children: &Bound<'_, PyMapping>,
+    ) -> PyResult<Py<Self>> {
+        let state = py
+            .get_type::<JsonnetObject>()
+            .call_method1("from_python", (value,))?
+            .extract::<JsonnetObject>()?;
+        let init = PyClassInitializer::from(state).add_subclass(Self {
+            children: Arc::new(child_storage_data(children)?),
+        });
+
+        Py::new(py, init)
+    }
+
+    /// Pickle evaluated fields, same as `JsonnetObject`, together with the child tries.
+    fn __reduce__<'py>(
+        slf: &Bound<'py, Self>,
+    ) -> PyResult<(Bound<'py, PyAny>, (Bound<'py, PyAny>, Bound<'py, PyDict>))> {
+        let py = slf.py();
+        let restore = py.get_type::<Self>().getattr("_restore")?;
+        let kwargs = [("hex_bytes", false)].into_py_dict(py)?;
+        let value = slf.call_method("to_python", (), Some(&kwargs))?;
+
+        let children = PyDict::new(py);
+        for (id, storage) in slf.borrow().children.iter() {
+            let data = PyDict::new(py);
+            for (key, value) in storage {
+                data.set_item(PyBytes::new(py, &key.0), PyBytes::new(py, &value.0))?;
+            }
+            children.set_item(PyBytes::new(py, &id.0), data)?;
+        }
+
+        Ok((restore, (value, children)))
+    }
+
+    fn __copy__(slf: PyRef<'_, Self>) -> PyResult<Py<Self>> {
+        let state: &JsonnetObject = slf.as_ref();
+        let init = PyClassInitializer::from(state.clone()).add_subclass(Self {
+            children: Arc::clone(&slf.children),
+        });
+
+        Py::new(slf.py(), init)
+    }
+
+    fn __deepcopy__(slf: PyRef<'_, Self>, _memo: &Bound<'_, PyAny>) -> PyResult<Py<Self>> {
+        Self::__copy__(slf)
     }
 }
 
+/// Decode raw storage with the given metadata.
+///
+/// Default child tries from `children` are available as `_child` of the result.
 #[pyfunction]
-#[pyo3(signature = (meta, data, opts=None))]
+#[pyo3(signature = (meta, data, opts=None, children=None))]
 pub fn dump(
+    py: Python<'_>,
     meta: Either<JsonnetObject, Vec<u8>>,
-    data: BTreeMap<Vec<u8>, Vec<u8>>,
+    data: &Bound<'_, PyAny>,
+    opts: Option<ChainOpts>,
+    children: Option<&Bound<'_, PyMapping>>,
+) -> PyResult<Py<DumpState>> {
+    let state = RawState {
+        top: storage_data(data)?,
+        children: children
+            .map(child_storage_data)
+            .transpose()?
+            .unwrap_or_default(),
+    };
+
+    dump_state(py, meta, state, opts)
+}
+
+/// Same as [`dump`], but storage is read from `genesis.raw` of the chain spec.
+///
+/// Metadata should be passed explicitly, because it can only
+/// be obtained by executing the runtime code.
+#[pyfunction]
+#[pyo3(signature = (path, meta, opts=None))]
+pub fn dump_from_chain_spec(
+    py: Python<'_>,
+    path: PathBuf,
+    meta: Either<JsonnetObject, Vec<u8>>,
+    opts: Option<ChainOpts>,
+) -> PyResult<Py<DumpState>> {
+    let state = py.allow_threads(|| raw_state::chain_spec_storage(&path))?;
+    dump_state(py, meta, state, opts)
+}
+
+/// Same as [`dump`], but storage is read from the try-runtime snapshot.
+///
+/// Metadata should be passed explicitly, because it can only
+/// be obtained by executing the runtime code.
+#[pyfunction]
+#[pyo3(signature = (path, meta, opts=None))]
+pub fn dump_from_snapshot(
+    py: Python<'_>,
+    path: PathBuf,
+    meta: Either<JsonnetObject, Vec<u8>>,
+    opts: Option<ChainOpts>,
+) -> PyResult<Py<DumpState>> {
+    let state = py.allow_threads(|| raw_state::snapshot_storage(&path))?;
+    dump_state(py, meta, state, opts)
+}
+
+fn dump_state(
+    py: Python<'_>,
+    meta: Either<JsonnetObject, Vec<u8>>,
+    state: RawState,
+    opts: Option<ChainOpts>,
+) -> PyResult<Py<DumpState>> {
+    let RawState { top, children } = state;
+
+    let storage = execute_jsonnet(py, move |_| dump_storage(meta, top, opts))?;
+    let init = PyClassInitializer::from(storage).add_subclass(DumpState {
+        children: Arc::new(children),
+    });
+
+    Py::new(py, init)
+}
+
+fn dump_storage(
+    meta: Either<JsonnetObject, Vec<u8>>,
+    storage: RawStorage,
     opts: Option<ChainOpts>,
 ) -> PyResult<JsonnetObject> {
-    execute_jsonnet(|_| {
-        let meta = match meta {
-            Either::Left(l) => jrsonnet_evaluator::typed::Either2::A(l.0),
-            Either::Right(r) => jrsonnet_evaluator::typed::Either2::B(Hex(r)),
-        };
+    let meta = || match &meta {
+        Either::Left(l) => {
+            jrsonnet_evaluator::typed::Either2::A(jrsonnet_evaluator::ObjValue::clone(&l.0))
+        }
+        Either::Right(r) => jrsonnet_evaluator::typed::Either2::B(Hex(r.clone())),
+    };
 
-        // SAFETY: Vec<u8> and Hex have the same layout and size.
-        let data =
-            unsafe { core::mem::transmute::<BTreeMap<Vec<u8>, Vec<u8>>, BTreeMap<Hex, Hex>>(data) };
+    let opts = || opts.clone().map(Into::into);
 
-        let opts = opts.map(Into::into);
+    let dump = chainql_core::builtin_dump(meta(), storage, opts()).map_err(|err| {
+        // Empty storage can not be decoded only because of the metadata.
+        match chainql_core::builtin_dump(meta(), RawStorage::new(), opts()) {
+            Ok(_) => chainql_error(&err, |message| storage_decode_error(None, message)),
+            Err(_) => chainql_error(&err, metadata_decode_error),
+        }
+    })?;
 
-        chainql_core::builtin_dump(meta, data, opts)
-            .map(JsonnetObject)
-            .map_err(|err| PyBaseException::new_err(err.to_string()))
-    })
+    Ok(JsonnetObject::storage(dump))
+}
+
+/// Collect storage entries from a mapping or an iterable of `(key, value)` pairs.
+///
+/// Keys and values may be any buffer-protocol objects or `0x`-prefixed hex strings.
+fn storage_data(data: &Bound<'_, PyAny>) -> PyResult<BTreeMap<Hex, Hex>> {
+    let py = data.py();
+
+    let entries = match data.downcast::<PyMapping>() {
+        Ok(mapping) => mapping.call_method0("items")?.try_iter()?,
+        Err(_) => data.try_iter().map_err(|err| {
+            type_error(py, "data should be a mapping or an iterable of pairs", err)
+        })?,
+    };
+
+    let mut storage = BTreeMap::new();
+    for (index, entry) in entries.enumerate() {
+        let (key, value) = entry?
+            .extract::<(Bound<'_, PyAny>, Bound<'_, PyAny>)>()
+            .map_err(|err| {
+                type_error(
+                    py,
+                    format!("entry #{index} should be a (key, value) pair"),
+                    err,
+                )
+            })?;
+
+        let key = HexBytes::extract(&key).map_err(|err| {
+            let key = key
+                .repr()
+                .map_or_else(|_| "<unprintable>".into(), |r| r.to_string());
+            type_error(
+                py,
+                format!("invalid storage key {key} at entry #{index}"),
+                err,
+            )
+        })?;
+
+        let value = HexBytes::extract(&value)
+            .map_err(|err| type_error(py, format!("invalid value of storage key {key}"), err))?;
+
+        storage.insert(Hex(key.0), Hex(value.0));
+    }
+
+    Ok(storage)
+}
+
+/// Collect default child tries from a mapping of child trie id to its storage entries.
+fn child_storage_data(
+    children: &Bound<'_, PyMapping>,
+) -> PyResult<BTreeMap<Hex, BTreeMap<Hex, Hex>>> {
+    let py = children.py();
+
+    children
+        .items()?
+        .iter()
+        .map(|entry| {
+            let (id, data) = entry.extract::<(Bound<'_, PyAny>, Bound<'_, PyAny>)>()?;
+
+            let id = HexBytes::extract(&id)
+                .map_err(|err| type_error(py, "invalid child trie id", err))?;
+            let data = storage_data(&data).map_err(|err| {
+                type_error(py, format!("invalid storage of the child trie {id}"), err)
+            })?;
+
+            Ok((Hex(child_id(&id.0).to_vec()), data))
+        })
+        .collect()
 }
@@ -0,0 +1,305 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    errors::rpc_call_error,
+    hex_bytes::HexBytes,
+    jsonnet_tokio::{block_on, cancellable},
+    raw_state::RawStorage,
+    rpc::{paged, Rpc},
+    utils::value_error,
+};
+use chainql_core::hex::{from_hex, to_hex, Hex};
+use pyo3::{exceptions::PyKeyError, prelude::*, types::PyIterator};
+use std::{collections::BTreeMap, sync::Arc};
+
+/// Prefix of the default child trie roots in the main trie.
+pub const DEFAULT_CHILD_PREFIX: &[u8] = b":child_storage:default:";
+
+/// Number of keys, requested by a single call, when `key_page_size` is not set.
+///
+/// Substrate nodes reject `state_getKeysPaged` requests of more keys.
+pub const DEFAULT_PAGE_SIZE: u32 = 1000;
+
+/// Child trie id without the `:child_storage:default:` prefix.
+pub fn child_id(key: &[u8]) -> &[u8] {
+    key.strip_prefix(DEFAULT_CHILD_PREFIX).unwrap_or(key)
+}
+
+/// Where the child tries are read from.
+#[derive(Clone)]
+enum Source {
+    /// Block state of the node
+    Node {
+        rpc: Rpc,
+        block: String,
+        page_size: u32,
+    },
+    /// Child tries, passed to `dump`, keyed by id
+    Dump(Arc<BTreeMap<Hex, RawStorage>>),
+}
+
+/// Default child tries of the state, available as `state._child`
+#[pyclass(frozen, module = "chainql")]
+pub struct ChildTries {
+    source: Source,
+}
+
+impl ChildTries {
+    /// Child tries of the node state at `block`, `page_size` should be positive.
+    pub fn new(rpc: Rpc, block: String, page_size: Option<u32>) -> Self {
+        Self {
+            source: Source::Node {
+                rpc,
+                block,
+                page_size: page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+            },
+        }
+    }
+
+    /// Child tries of the storage dump.
+    pub fn dump(children: Arc<BTreeMap<Hex, RawStorage>>) -> Self {
+        Self {
+            source: Source::Dump(children),
+        }
+    }
+}
+
+#[pymethods]
+impl ChildTries {
+    /// Child trie with the given id, with or without `:child_storage:default:` prefix.
+    fn __getitem__(&self, key: &Bound<'_, PyAny>) -> PyResult<ChildTrie> {
+        let key = HexBytes::extract(key)?;
+
+        Ok(ChildTrie {
+            source: self.source.clone(),
+            id: child_id(&key.0).to_vec(),
+        })
+    }
+
+    /// Ids of every default child trie in the state
+    fn keys(&self, py: Python<'_>) -> PyResult<Vec<HexBytes>> {
+        let (rpc, block, page_size) = match &self.source {
+            Source::Node {
+                rpc,
+                block,
+                page_size,
+            } => (rpc, block, *page_size),
+            Source::Dump(children) => {
+                return Ok(children.keys().map(|id| HexBytes(id.0.clone())).collect())
+            }
+        };
+        let prefix = &to_hex(DEFAULT_CHILD_PREFIX);
+
+        let keys = py.allow_threads(|| {
+            block_on(cancellable(paged(page_size, |start| async move {
+                rpc.keys_paged(prefix, page_size, start.as_deref(), block)
+                    .await
+            })))
+        })?;
+
+        keys.iter()
+            .map(|key| Ok(HexBytes(child_id(&decode(key)?.0).to_vec())))
+            .collect()
+    }
+
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        self.keys(py)?.into_pyobject(py)?.try_iter()
+    }
+
+    fn __repr__(&self) -> String {
+        match &self.source {
+            Source::Node { block, .. } => format!("ChildTries(block='{block}')"),
+            Source::Dump(_) => "ChildTries(dump)".to_owned(),
+        }
+    }
+}
+
+/// Default child trie of the state
+///
+/// Runtime metadata doesn't describe child trie contents,
+/// so keys and values are returned as raw bytes.
+#[pyclass(frozen, module = "chainql")]
+pub struct ChildTrie {
+    source: Source,
+    id: Vec<u8>,
+}
+
+impl ChildTrie {
+    /// Prefixed child storage key, as expected by the `childstate_*` calls.
+    fn storage_key(&self) -> String {
+        to_hex(&[DEFAULT_CHILD_PREFIX, &self.id].concat())
+    }
+
+    /// Storage of the dumped child trie, missing trie is empty.
+    fn dumped<'a>(&self, children: &'a BTreeMap<Hex, RawStorage>) -> Option<&'a RawStorage> {
+        children.get(&Hex(self.id.clone()))
+    }
+
+    fn value(&self, py: Python<'_>, key: &Bound<'_, PyAny>) -> PyResult<Option<HexBytes>> {
+        let key = HexBytes::extract(key)?;
+
+        let (rpc, block) = match &self.source {
+            Source::Node { rpc, block, .. } => (rpc, block),
+            Source::Dump(children) => {
+                let value = self
+                    .dumped(children)
+                    .and_then(|storage| storage.get(&Hex(key.0)));
+                return Ok(value.map(|value| HexBytes(value.0.clone())));
+            }
+        };
+        let (child, key) = (self.storage_key(), to_hex(&key.0));
+
+        let value =
+            py.allow_threads(|| block_on(cancellable(rpc.child_storage(&child, &key, block))))?;
+
+        value.as_deref().map(decode).transpose()
+    }
+
+    /// Entries of the child trie, which keys start with `prefix`, values are fetched only `with_values`.
+    fn entries(
+        &self,
+        py: Python<'_>,
+        prefix: Option<&Bound<'_, PyAny>>,
+        with_values: bool,
+    ) -> PyResult<Vec<(HexBytes, Option<HexBytes>)>> {
+        let prefix = prefix.map(HexBytes::extract).transpose()?;
+        let prefix = prefix.map_or_else(Vec::new, |prefix| prefix.0);
+
+        let (rpc, block, page_size) = match &self.source {
+            Source::Node {
+                rpc,
+                block,
+                page_size,
+            } => (rpc, block, *page_size),
+            Source::Dump(children) => {
+                let Some(storage) = self.dumped(children) else {
+                    return Ok(Vec::new());
+                };
+
+                return Ok(storage
+                    .range(Hex(prefix.clone())..)
+                    .take_while(|(key, _)| key.0.starts_with(&prefix))
+                    .map(|(key, value)| {
+                        let value = with_values.then(|| HexBytes(value.0.clone()));
+                        (HexBytes(key.0.clone()), value)
+                    })
+                    .collect());
+            }
+        };
+        let (child, prefix) = (&self.storage_key(), &to_hex(&prefix));
+
+        let entries = py.allow_threads(|| {
+            block_on(cancellable(async {
+                let keys = paged(page_size, |start| async move {
+                    rpc.child_keys_paged(child, prefix, page_size, start.as_deref(), block)
+                        .await
+                })
+                .await?;
+
+                if !with_values {
+                    return Ok(keys.into_iter().map(|key| (key, None)).collect());
+                }
+
+                let mut entries = Vec::with_capacity(keys.len());
+                for keys in keys.chunks(page_size as usize) {
+                    let values = rpc.child_storage_entries(child, keys, block).await?;
+                    entries.extend(keys.iter().cloned().zip(values));
+                }
+
+                PyResult::Ok(entries)
+            }))
+        })?;
+
+        entries
+            .iter()
+            .map(|(key, value)| Ok((decode(key)?, value.as_deref().map(decode).transpose()?)))
+            .collect()
+    }
+}
+
+#[pymethods]
+impl ChildTrie {
+    /// Child trie id without the `:child_storage:default:` prefix
+    #[getter]
+    fn id(&self) -> HexBytes {
+        HexBytes(self.id.clone())
+    }
+
+    fn __getitem__(&self, py: Python<'_>, key: &Bound<'_, PyAny>) -> PyResult<HexBytes> {
+        self.value(py, key)?
+            .ok_or_else(|| PyKeyError::new_err(key.clone().unbind()))
+    }
+
+    #[pyo3(signature = (key, default=None))]
+    fn get(
+        &self,
+        py: Python<'_>,
+        key: &Bound<'_, PyAny>,
+        default: Option<PyObject>,
+    ) -> PyResult<PyObject> {
+        match self.value(py, key)? {
+            Some(value) => Ok(value.into_pyobject(py)?.into_any().unbind()),
+            None => Ok(default.unwrap_or_else(|| py.None())),
+        }
+    }
+
+    fn __contains__(&self, py: Python<'_>, key: &Bound<'_, PyAny>) -> PyResult<bool> {
+        self.value(py, key).map(|value| value.is_some())
+    }
+
+    /// Keys of the child trie, starting with `prefix`
+    #[pyo3(signature = (prefix=None))]
+    fn keys(&self, py: Python<'_>, prefix: Option<&Bound<'_, PyAny>>) -> PyResult<Vec<HexBytes>> {
+        let entries = self.entries(py, prefix, false)?;
+        Ok(entries.into_iter().map(|(key, _)| key).collect())
+    }
+
+    /// `(key, value)` pairs of the child trie, which keys start with `prefix`
+    #[pyo3(signature = (prefix=None))]
+    fn items(
+        &self,
+        py: Python<'_>,
+        prefix: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<Vec<(HexBytes, HexBytes)>> {
+        let entries = self.entries(py, prefix, true)?;
+
+        entries
+            .into_iter()
+            .map(|(key, value)| match value {
+                Some(value) => Ok((key, value)),
+                None => Err(rpc_call_error(
+                    None,
+                    format!("node returned no value for the listed key {key}"),
+                )),
+            })
+            .collect()
+    }
+
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        self.keys(py, None)?.into_pyobject(py)?.try_iter()
+    }
+
+    fn __repr__(&self) -> String {
+        let id = HexBytes(self.id.clone());
+        match &self.source {
+            Source::Node { block, .. } => format!("ChildTrie(id='{id}', block='{block}')"),
+            Source::Dump(_) => format!("ChildTrie(id='{id}')"),
+        }
+    }
+}
+
+fn decode(hex: &str) -> PyResult<HexBytes> {
+    from_hex(hex).map(HexBytes).map_err(value_error)
+}
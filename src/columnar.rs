@@ -0,0 +1,448 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::utils::jsonnet_error;
+use arrow::{
+    array::{
+        ArrayRef, BooleanBuilder, Decimal128Builder, Float64Builder, Int64Builder, NullArray,
+        StringBuilder,
+    },
+    datatypes::{DataType, Field, Schema, DECIMAL128_MAX_PRECISION},
+    record_batch::RecordBatch,
+};
+use jrsonnet_evaluator::{manifest::JsonFormat, ObjValue, Val};
+use num_bigint::BigInt;
+use parquet::arrow::ArrowWriter;
+use pyo3::{exceptions::PyValueError, prelude::*};
+use std::{fs::File, path::Path, sync::Arc};
+
+/// Name of the column with storage map keys.
+const KEY_COLUMN: &str = "key";
+
+/// Name of the column with values, which are not structs.
+const VALUE_COLUMN: &str = "value";
+
+/// Largest integer, which f64 represents exactly.
+const MAX_SAFE_INTEGER: f64 = ((1u64 << f64::MANTISSA_DIGITS) - 1) as f64;
+
+/// Single evaluated value of the table.
+enum Cell {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    BigInt(BigInt),
+    /// Strings and arrays, manifested as JSON
+    Str(String),
+}
+
+impl Cell {
+    fn new(value: Val) -> jrsonnet_evaluator::Result<Self> {
+        Ok(match value {
+            Val::Null => Self::Null,
+            Val::Bool(b) => Self::Bool(b),
+            Val::Num(num) => {
+                let num = num.get();
+                if num.fract() == 0.0 && num.abs() <= MAX_SAFE_INTEGER {
+                    Self::Int(num as i64)
+                } else {
+                    Self::Float(num)
+                }
+            }
+            Val::BigInt(num) => Self::BigInt(*num),
+            Val::Str(s) => Self::Str(s.into_flat().as_str().to_owned()),
+            // Arrays and functions, nested structs are flattened by `flatten_fields`.
+            value => Self::Str(value.manifest(JsonFormat::minify(true))?),
+        })
+    }
+
+    /// Value as i128, if it fits into the decimal128 column.
+    fn decimal(&self) -> Option<i128> {
+        let max = 10i128.pow(DECIMAL128_MAX_PRECISION as u32) - 1;
+        match self {
+            Self::Int(num) => Some(i128::from(*num)),
+            Self::BigInt(num) => i128::try_from(num).ok().filter(|num| num.abs() <= max),
+            _ => None,
+        }
+    }
+
+    fn to_text(&self) -> Option<String> {
+        match self {
+            Self::Null => None,
+            Self::Bool(b) => Some(b.to_string()),
+            Self::Int(num) => Some(num.to_string()),
+            Self::Float(num) => Some(num.to_string()),
+            Self::BigInt(num) => Some(num.to_string()),
+            Self::Str(s) => Some(s.clone()),
+        }
+    }
+}
+
+/// Arrow type of the column, inferred from all of its cells.
+#[derive(Clone, Copy, PartialEq)]
+enum ColumnType {
+    Null,
+    Bool,
+    Int,
+    Float,
+    Decimal,
+    Str,
+}
+
+impl ColumnType {
+    fn of(cell: &Cell) -> Self {
+        match cell {
+            Cell::Null => Self::Null,
+            Cell::Bool(_) => Self::Bool,
+            Cell::Int(_) => Self::Int,
+            Cell::Float(_) => Self::Float,
+            Cell::BigInt(_) if cell.decimal().is_some() => Self::Decimal,
+            Cell::BigInt(_) | Cell::Str(_) => Self::Str,
+        }
+    }
+
+    /// Common type, which represents both types without loss.
+    fn merge(self, other: Self) -> Self {
+        use ColumnType::*;
+
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Null, t) | (t, Null) => t,
+            (Int, Float) | (Float, Int) => Float,
+            (Int, Decimal) | (Decimal, Int) => Decimal,
+            _ => Str,
+        }
+    }
+
+    fn data_type(self) -> DataType {
+        match self {
+            Self::Null => DataType::Null,
+            Self::Bool => DataType::Boolean,
+            Self::Int => DataType::Int64,
+            Self::Float => DataType::Float64,
+            Self::Decimal => DataType::Decimal128(DECIMAL128_MAX_PRECISION, 0),
+            Self::Str => DataType::Utf8,
+        }
+    }
+}
+
+/// Flatten storage map into a record batch.
+///
+/// Every row is a map entry, `key` column holds the storage key.
+/// Struct values are split into columns by fields, nested structs are
+/// flattened into `outer.inner` columns, arrays are stored as JSON strings
+/// and other values go to the `value` column. Fields named `key` or `value`
+/// are prefixed with `value.` together with their nested fields, so they
+/// don't collide with these columns.
+/// Balances, which don't fit into `decimal128(38, 0)`, are stored as strings.
+pub fn storage_map_to_batch(map: &ObjValue) -> PyResult<RecordBatch> {
+    let mut columns = Columns::default();
+    let mut rows = Vec::new();
+
+    for key in map.fields_ex(false, true) {
+        let value = map
+            .get(key.clone())
+            .map_err(jsonnet_error)?
+            .expect("field should exist");
+
+        let mut row = vec![(
+            columns.index(KEY_COLUMN),
+            Cell::Str(key.as_str().to_owned()),
+        )];
+
+        match value {
+            Val::Obj(value) => {
+                for (name, value) in flatten_fields(&value).map_err(jsonnet_error)? {
+                    let top = name.split('.').next().unwrap_or_default();
+                    let name = match top {
+                        KEY_COLUMN | VALUE_COLUMN => format!("{VALUE_COLUMN}.{name}"),
+                        _ => name,
+                    };
+                    row.push((columns.index(&name), value));
+                }
+            }
+            value => {
+                let cell = Cell::new(value).map_err(jsonnet_error)?;
+                row.push((columns.index(VALUE_COLUMN), cell));
+            }
+        }
+
+        rows.push(row);
+    }
+
+    let names = columns.names;
+    let mut cells_by_column: Vec<Vec<&Cell>> = vec![Vec::with_capacity(rows.len()); names.len()];
+    for row in &rows {
+        let mut cells = vec![&Cell::Null; names.len()];
+        for (column, cell) in row {
+            cells[*column] = cell;
+        }
+        for (column, cell) in cells.into_iter().enumerate() {
+            cells_by_column[column].push(cell);
+        }
+    }
+
+    let (fields, arrays): (Vec<_>, Vec<_>) = names
+        .iter()
+        .zip(cells_by_column)
+        .filter(|(name, cells)| !is_null_struct(name, cells, &names))
+        .map(|(name, cells)| {
+            let ty = cells
+                .iter()
+                .map(|cell| ColumnType::of(cell))
+                .fold(ColumnType::Null, ColumnType::merge);
+
+            let field = Field::new(name.as_str(), ty.data_type(), true);
+            (field, build_array(ty, &cells))
+        })
+        .unzip();
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), arrays)
+        .map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+/// Column names in the order they were first seen.
+#[derive(Default)]
+struct Columns {
+    names: Vec<String>,
+}
+
+impl Columns {
+    fn index(&mut self, name: &str) -> usize {
+        match self.names.iter().position(|n| n == name) {
+            Some(column) => column,
+            None => {
+                self.names.push(name.to_owned());
+                self.names.len() - 1
+            }
+        }
+    }
+}
+
+/// Fields of the struct with nested structs flattened into `outer.inner` names.
+fn flatten_fields(value: &ObjValue) -> jrsonnet_evaluator::Result<Vec<(String, Cell)>> {
+    let mut fields = Vec::new();
+
+    for field in value.fields_ex(false, true) {
+        let name = field.as_str().to_owned();
+        match value.get(field)?.expect("field should exist") {
+            Val::Obj(nested) => {
+                for (nested_name, cell) in flatten_fields(&nested)? {
+                    fields.push((format!("{name}.{nested_name}"), cell));
+                }
+            }
+            value => fields.push((name, Cell::new(value)?)),
+        }
+    }
+
+    Ok(fields)
+}
+
+/// Whether the column holds only nulls of the optional struct, which fields have own columns.
+fn is_null_struct(name: &str, cells: &[&Cell], names: &[String]) -> bool {
+    let prefix = format!("{name}.");
+    cells.iter().all(|cell| matches!(cell, Cell::Null))
+        && names.iter().any(|other| other.starts_with(&prefix))
+}
+
+fn build_array(ty: ColumnType, cells: &[&Cell]) -> ArrayRef {
+    match ty {
+        ColumnType::Null => Arc::new(NullArray::new(cells.len())),
+        ColumnType::Bool => {
+            let mut builder = BooleanBuilder::with_capacity(cells.len());
+            for cell in cells {
+                builder.append_option(match cell {
+                    Cell::Bool(b) => Some(*b),
+                    _ => None,
+                });
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Int => {
+            let mut builder = Int64Builder::with_capacity(cells.len());
+            for cell in cells {
+                builder.append_option(match cell {
+                    Cell::Int(num) => Some(*num),
+                    _ => None,
+                });
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Float => {
+            let mut builder = Float64Builder::with_capacity(cells.len());
+            for cell in cells {
+                builder.append_option(match cell {
+                    Cell::Int(num) => Some(*num as f64),
+                    Cell::Float(num) => Some(*num),
+                    _ => None,
+                });
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Decimal => {
+            let mut builder = Decimal128Builder::with_capacity(cells.len())
+                .with_precision_and_scale(DECIMAL128_MAX_PRECISION, 0)
+                .expect("precision and scale should be valid");
+            for cell in cells {
+                builder.append_option(cell.decimal());
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Str => {
+            let mut builder = StringBuilder::new();
+            for cell in cells {
+                builder.append_option(cell.to_text());
+            }
+            Arc::new(builder.finish())
+        }
+    }
+}
+
+/// Write record batch to the parquet file.
+pub fn write_parquet(batch: &RecordBatch, path: &Path) -> PyResult<()> {
+    let parquet_error = |err: parquet::errors::ParquetError| PyValueError::new_err(err.to_string());
+
+    let file = File::create(path)?;
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), None).map_err(parquet_error)?;
+    writer.write(batch).map_err(parquet_error)?;
+    writer.close().map_err(parquet_error)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Array, Decimal128Array, Int64Array, StringArray};
+    use jrsonnet_evaluator::{val::NumValue, ObjValueBuilder};
+
+    fn num(num: f64) -> Val {
+        Val::Num(NumValue::new(num).expect("finite"))
+    }
+
+    fn big(num: u128) -> Val {
+        Val::BigInt(Box::new(BigInt::from(num)))
+    }
+
+    fn obj(fields: Vec<(&str, Val)>) -> Val {
+        let mut obj = ObjValueBuilder::new();
+        for (name, value) in fields {
+            obj.field(name).value(value);
+        }
+        Val::Obj(obj.build())
+    }
+
+    fn map(entries: Vec<(&str, Val)>) -> ObjValue {
+        match obj(entries) {
+            Val::Obj(obj) => obj,
+            _ => unreachable!(),
+        }
+    }
+
+    fn column_names(batch: &RecordBatch) -> Vec<String> {
+        let schema = batch.schema();
+        schema.fields().iter().map(|f| f.name().clone()).collect()
+    }
+
+    fn column<'a, T: 'static>(batch: &'a RecordBatch, name: &str) -> &'a T {
+        batch
+            .column_by_name(name)
+            .unwrap_or_else(|| panic!("column {name} should exist"))
+            .as_any()
+            .downcast_ref::<T>()
+            .unwrap_or_else(|| panic!("column {name} has unexpected type"))
+    }
+
+    #[test]
+    fn plain_values() {
+        let batch =
+            storage_map_to_batch(&map(vec![("0x01", num(1.0)), ("0x02", num(2.0))])).unwrap();
+
+        assert_eq!(column_names(&batch), ["key", "value"]);
+        let keys = column::<StringArray>(&batch, "key");
+        assert_eq!((keys.value(0), keys.value(1)), ("0x01", "0x02"));
+        let values = column::<Int64Array>(&batch, "value");
+        assert_eq!((values.value(0), values.value(1)), (1, 2));
+    }
+
+    #[test]
+    fn nested_structs() {
+        let account = |free: u128, nonce: f64| {
+            obj(vec![
+                ("nonce", num(nonce)),
+                (
+                    "data",
+                    obj(vec![("free", big(free)), ("reserved", num(0.0))]),
+                ),
+            ])
+        };
+        let batch = storage_map_to_batch(&map(vec![
+            ("alice", account(u128::from(u64::MAX) * 1000, 1.0)),
+            ("bob", account(5, 2.0)),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            column_names(&batch),
+            ["key", "nonce", "data.free", "data.reserved"]
+        );
+        let free = column::<Decimal128Array>(&batch, "data.free");
+        assert_eq!(free.value(0), i128::from(u64::MAX) * 1000);
+        assert_eq!(free.value(1), 5);
+    }
+
+    #[test]
+    fn u128_overflowing_decimal() {
+        let batch = storage_map_to_batch(&map(vec![("total", big(u128::MAX))])).unwrap();
+
+        let values = column::<StringArray>(&batch, "value");
+        assert_eq!(values.value(0), u128::MAX.to_string());
+    }
+
+    #[test]
+    fn reserved_field_names() {
+        let batch = storage_map_to_batch(&map(vec![
+            (
+                "0x01",
+                obj(vec![
+                    ("key", num(1.0)),
+                    ("value", obj(vec![("key", num(2.0))])),
+                ]),
+            ),
+            ("0x02", num(3.0)),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            column_names(&batch),
+            ["key", "value.key", "value.value.key", "value"]
+        );
+        assert_eq!(column::<StringArray>(&batch, "key").value(0), "0x01");
+        assert_eq!(column::<Int64Array>(&batch, "value.key").value(0), 1);
+        assert_eq!(column::<Int64Array>(&batch, "value.value.key").value(0), 2);
+        assert_eq!(column::<Int64Array>(&batch, "value").value(1), 3);
+    }
+
+    #[test]
+    fn optional_struct() {
+        let batch = storage_map_to_batch(&map(vec![
+            ("some", obj(vec![("inner", obj(vec![("a", num(1.0))]))])),
+            ("none", obj(vec![("inner", Val::Null)])),
+        ]))
+        .unwrap();
+
+        assert_eq!(column_names(&batch), ["key", "inner.a"]);
+        assert!(column::<Int64Array>(&batch, "inner.a").is_null(1));
+    }
+}
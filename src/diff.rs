@@ -0,0 +1,159 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{jsonnet_py::jsonnet_to_py, utils::jsonnet_error};
+use jrsonnet_evaluator::{val::equals, IStr, ObjValue, Val};
+use pyo3::{
+    prelude::*,
+    types::{PyDict, PyTuple},
+};
+use std::collections::HashSet;
+
+/// Value at `path`, which differs between two objects.
+///
+/// Added values have no `old`, removed ones have no `new`.
+pub struct Change {
+    pub path: Vec<String>,
+    pub old: Option<Val>,
+    pub new: Option<Val>,
+}
+
+/// Compare visible fields of two objects, nested objects are compared recursively.
+pub fn diff_objects(
+    old: &ObjValue,
+    new: &ObjValue,
+    path: &mut Vec<String>,
+    changes: &mut Vec<Change>,
+) -> PyResult<()> {
+    for key in union_fields(Some(old), Some(new)) {
+        let old = old.get(key.clone()).map_err(jsonnet_error)?;
+        let new = new.get(key.clone()).map_err(jsonnet_error)?;
+
+        path.push(key.to_string());
+        diff_values(old, new, path, changes)?;
+        path.pop();
+    }
+
+    Ok(())
+}
+
+/// Compare two values at `path`, arrays and primitives are compared as a whole.
+pub fn diff_values(
+    old: Option<Val>,
+    new: Option<Val>,
+    path: &mut Vec<String>,
+    changes: &mut Vec<Change>,
+) -> PyResult<()> {
+    match (old, new) {
+        (Some(Val::Obj(old)), Some(Val::Obj(new))) => diff_objects(&old, &new, path, changes),
+        (old, new) => diff_whole(old, new, path, changes),
+    }
+}
+
+/// Compare entries of the storage item, which are nested by `keys` storage map keys.
+///
+/// Values are compared as a whole, so changes are reported at the storage keys.
+pub fn diff_entries(
+    old: Option<Val>,
+    new: Option<Val>,
+    keys: usize,
+    path: &mut Vec<String>,
+    changes: &mut Vec<Change>,
+) -> PyResult<()> {
+    // Missing map has no entries, so its keys are reported as added or removed.
+    let (old_map, new_map) = match (keys, &old, &new) {
+        (0, _, _) => return diff_whole(old, new, path, changes),
+        (_, Some(Val::Obj(old)), Some(Val::Obj(new))) => (Some(old), Some(new)),
+        (_, Some(Val::Obj(old)), None) => (Some(old), None),
+        (_, None, Some(Val::Obj(new))) => (None, Some(new)),
+        _ => return diff_whole(old, new, path, changes),
+    };
+
+    for key in union_fields(old_map, new_map) {
+        let get = |map: Option<&ObjValue>| {
+            let value = map.map(|map| map.get(key.clone())).transpose();
+            value.map(Option::flatten).map_err(jsonnet_error)
+        };
+
+        path.push(key.to_string());
+        diff_entries(get(old_map)?, get(new_map)?, keys - 1, path, changes)?;
+        path.pop();
+    }
+
+    Ok(())
+}
+
+fn diff_whole(
+    old: Option<Val>,
+    new: Option<Val>,
+    path: &mut [String],
+    changes: &mut Vec<Change>,
+) -> PyResult<()> {
+    match (old, new) {
+        (Some(old), Some(new)) if equals(&old, &new).map_err(jsonnet_error)? => Ok(()),
+        (None, None) => Ok(()),
+        (old, new) => {
+            changes.push(Change {
+                path: path.to_vec(),
+                old,
+                new,
+            });
+            Ok(())
+        }
+    }
+}
+
+/// Visible fields of both objects, without duplicates.
+fn union_fields(old: Option<&ObjValue>, new: Option<&ObjValue>) -> Vec<IStr> {
+    let mut keys = Vec::new();
+    let mut seen = HashSet::new();
+    for obj in [old, new].into_iter().flatten() {
+        keys.extend(
+            obj.fields_ex(false, true)
+                .into_iter()
+                .filter(|key| seen.insert(key.clone())),
+        );
+    }
+
+    keys
+}
+
+/// Convert changes to `{"added": {path: new}, "removed": {path: old}, "changed": {path: (old, new)}}`.
+///
+/// Paths are tuples of field names. Should be called on the evaluator thread.
+pub fn changes_to_py(py: Python<'_>, changes: Vec<Change>) -> PyResult<Bound<'_, PyDict>> {
+    let added = PyDict::new(py);
+    let removed = PyDict::new(py);
+    let changed = PyDict::new(py);
+
+    for Change { path, old, new } in changes {
+        let path = PyTuple::new(py, path)?;
+
+        match (old, new) {
+            (None, Some(new)) => added.set_item(path, jsonnet_to_py(py, new)?)?,
+            (Some(old), None) => removed.set_item(path, jsonnet_to_py(py, old)?)?,
+            (Some(old), Some(new)) => {
+                changed.set_item(path, (jsonnet_to_py(py, old)?, jsonnet_to_py(py, new)?))?
+            }
+            (None, None) => {}
+        }
+    }
+
+    let diff = PyDict::new(py);
+    diff.set_item("added", added)?;
+    diff.set_item("removed", removed)?;
+    diff.set_item("changed", changed)?;
+
+    Ok(diff)
+}
@@ -0,0 +1,314 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::jsonnet_tokio::current_job;
+use either::Either;
+use pyo3::{
+    create_exception,
+    exceptions::{PyKeyboardInterrupt, PyRuntimeError},
+    prelude::*,
+    types::PyTuple,
+    PyTypeInfo,
+};
+use std::{
+    cell::Cell,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+create_exception!(
+    chainql.errors,
+    ChainqlError,
+    PyRuntimeError,
+    "Base class for chainql errors"
+);
+
+create_exception!(
+    chainql.errors,
+    ConnectionError,
+    ChainqlError,
+    "Connection to the node failed"
+);
+
+create_exception!(
+    chainql.errors,
+    RpcError,
+    ChainqlError,
+    "Node returned an error for the RPC request"
+);
+
+create_exception!(
+    chainql.errors,
+    MetadataDecodeError,
+    ChainqlError,
+    "Runtime metadata can not be decoded"
+);
+
+create_exception!(
+    chainql.errors,
+    StorageDecodeError,
+    ChainqlError,
+    "Storage key or value can not be decoded"
+);
+
+create_exception!(
+    chainql.errors,
+    EvaluationError,
+    ChainqlError,
+    "Jsonnet evaluation failed"
+);
+
+create_exception!(
+    chainql.errors,
+    Cancelled,
+    PyKeyboardInterrupt,
+    "Evaluation was cancelled by Ctrl-C"
+);
+
+/// Create error of type `T` with `message` and structured attributes.
+///
+/// Every chainql error has `message` attribute, others are set by `attrs`.
+/// Subclasses of `ChainqlError` start with empty `trace` and without `path`.
+pub fn new_error<T: PyTypeInfo>(
+    message: String,
+    attrs: impl FnOnce(&Bound<'_, PyAny>) -> PyResult<()>,
+) -> PyErr {
+    Python::with_gil(|py| {
+        let err = PyErr::from_type(T::type_object(py), message.clone());
+
+        let value = err.value(py).as_any();
+        let defaults = || -> PyResult<()> {
+            if T::type_object(py).is_subclass_of::<ChainqlError>()? {
+                value.setattr("trace", Vec::<String>::new())?;
+                value.setattr("path", py.None())?;
+            }
+            Ok(())
+        };
+
+        match value
+            .setattr("message", message)
+            .and_then(|()| defaults())
+            .and_then(|()| attrs(value))
+        {
+            Ok(()) => err,
+            Err(attr_err) => attr_err,
+        }
+    })
+}
+
+/// Failure of the node request, made by chainql-core.
+///
+/// chainql-core reports every failure as a plain string, so node failures are
+/// observed by the local endpoint instead. Evaluation is serialized, so failure
+/// belongs to the job, which is running on the evaluator thread.
+pub enum NodeFailure {
+    /// Node returned an error with the JSON-RPC code and message
+    Rpc(i32, String),
+    /// Connection to the node failed
+    Connection,
+}
+
+/// Value, observed by the local endpoint during the evaluator job.
+type Observed<T> = Mutex<Option<(u64, T)>>;
+
+/// The last node failure and the job, during which it was observed.
+static NODE_FAILURE: Observed<NodeFailure> = Mutex::new(None);
+
+/// The last storage key, which value was read by chainql-core, and the job, which read it.
+static STORAGE_READ: Observed<String> = Mutex::new(None);
+
+fn observe<T>(slot: &Observed<T>, value: T) {
+    *slot.lock().unwrap_or_else(PoisonError::into_inner) = Some((current_job(), value));
+}
+
+/// Value, observed during the current job.
+fn observed<T>(slot: &Observed<T>) -> Option<T> {
+    let observed = slot.lock().unwrap_or_else(PoisonError::into_inner).take();
+    observed
+        .filter(|(job, _)| *job == current_job())
+        .map(|(_, value)| value)
+}
+
+/// Forget observations of the previous jobs.
+pub fn reset_observations() {
+    NODE_FAILURE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    STORAGE_READ
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    PYTHON_ERROR.take();
+}
+
+/// Remember failure of the chainql-core request.
+pub fn record_node_failure(failure: NodeFailure) {
+    observe(&NODE_FAILURE, failure);
+}
+
+/// Take node failure of the current job, which caused the error with `message`.
+///
+/// chainql-core may recover from the failed request, so RPC errors are attributed
+/// only to the errors, which mention them.
+pub fn take_node_failure(message: &str) -> Option<NodeFailure> {
+    observed(&NODE_FAILURE).filter(|failure| match failure {
+        NodeFailure::Rpc(_, rpc_message) => message.contains(rpc_message.as_str()),
+        NodeFailure::Connection => true,
+    })
+}
+
+/// Remember storage key, which value chainql-core read from the node.
+///
+/// Reads of several keys at once are not attributable to a single key, so they reset it.
+pub fn record_storage_read(key: Option<String>) {
+    match key {
+        Some(key) => observe(&STORAGE_READ, key),
+        None => {
+            STORAGE_READ
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take();
+        }
+    }
+}
+
+/// The last storage key, which value was read during the current job.
+pub fn last_storage_read() -> Option<String> {
+    let mut slot = STORAGE_READ.lock().unwrap_or_else(PoisonError::into_inner);
+    match &*slot {
+        Some((job, key)) if *job == current_job() => Some(key.clone()),
+        _ => {
+            slot.take();
+            None
+        }
+    }
+}
+
+thread_local! {
+    /// Python exception, raised by the callable, which was passed to jsonnet.
+    ///
+    /// Jsonnet errors can only hold a message, so the exception is kept aside
+    /// and chained to the chainql error. Callables are called on the evaluator
+    /// thread, so the exception belongs to its current job.
+    static PYTHON_ERROR: Cell<Option<PyErr>> = const { Cell::new(None) };
+}
+
+/// Remember exception of the python callable.
+pub fn record_python_error(err: PyErr) {
+    PYTHON_ERROR.set(Some(err));
+}
+
+/// Take exception of the python callable, raised during the current job, if any.
+pub fn take_python_error() -> Option<PyErr> {
+    PYTHON_ERROR.take()
+}
+
+pub fn cancelled_error() -> PyErr {
+    new_error::<Cancelled>("cancelled".to_owned(), |_| Ok(()))
+}
+
+pub fn evaluation_error(message: String) -> PyErr {
+    new_error::<EvaluationError>(message, |_| Ok(()))
+}
+
+pub fn connection_error(message: String) -> PyErr {
+    new_error::<ConnectionError>(message, |_| Ok(()))
+}
+
+pub fn rpc_call_error(code: Option<i32>, message: String) -> PyErr {
+    new_error::<RpcError>(message, |err| err.setattr("code", code))
+}
+
+pub fn metadata_decode_error(message: String) -> PyErr {
+    new_error::<MetadataDecodeError>(message, |_| Ok(()))
+}
+
+pub fn storage_decode_error(key: Option<String>, message: String) -> PyErr {
+    new_error::<StorageDecodeError>(message, |err| err.setattr("key", key))
+}
+
+/// Attach jsonnet stack trace to the error.
+///
+/// Frames are available as `trace` attribute and as `__notes__`, so
+/// python 3.11+ prints them with the traceback. Cancellation is not
+/// an evaluation failure, so `Cancelled` is left without the trace.
+pub fn with_trace(err: PyErr, trace: Vec<String>) -> PyErr {
+    Python::with_gil(|py| {
+        if err.is_instance_of::<Cancelled>(py) {
+            return err;
+        }
+
+        let value = err.value(py).as_any();
+
+        let attach = || -> PyResult<()> {
+            let notes = trace.iter().map(|frame| format!("jsonnet: {frame}"));
+            match value.getattr("__notes__") {
+                Ok(existing) => {
+                    for note in notes {
+                        existing.call_method1("append", (note,))?;
+                    }
+                }
+                Err(_) => value.setattr("__notes__", notes.collect::<Vec<_>>())?,
+            }
+
+            value.setattr("trace", trace.clone())
+        };
+
+        match attach() {
+            Ok(()) => err,
+            Err(attr_err) => attr_err,
+        }
+    })
+}
+
+/// Keys and indices, which lead to the value from the object, returned to python.
+///
+/// For storage these are pallet, storage entry and key of the map.
+#[derive(Clone, Default)]
+pub struct ValuePath(Arc<Vec<Either<String, usize>>>);
+
+impl ValuePath {
+    /// Path to the nested field or element.
+    pub fn join(&self, segment: Either<String, usize>) -> Self {
+        let mut path = Vec::clone(&self.0);
+        path.push(segment);
+        Self(Arc::new(path))
+    }
+
+    pub fn segments(&self) -> &[Either<String, usize>] {
+        &self.0
+    }
+
+    /// Set `path` of the chainql error, unless it was already set by the nested value.
+    pub fn attach(&self, err: PyErr) -> PyErr {
+        Python::with_gil(|py| {
+            let value = err.value(py).as_any();
+            if !value.is_instance_of::<ChainqlError>() {
+                return err;
+            }
+
+            let attach = || -> PyResult<()> {
+                if value.getattr("path")?.is_none() {
+                    value.setattr("path", PyTuple::new(py, self.0.iter().cloned())?)?;
+                }
+                Ok(())
+            };
+
+            match attach() {
+                Ok(()) => err,
+                Err(attr_err) => attr_err,
+            }
+        })
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    jsonnet_py::{jsonnet_to_pyobject, py_to_jsonnet},
+    jsonnet_tokio::{cancellation_notifier, execute_jsonnet},
+    utils::{jsonnet_error, type_error},
+};
+use chainql_core::{hex::Hex, ChainOpts};
+use jrsonnet_evaluator::{
+    apply_tla,
+    function::{builtin, TlaArg},
+    gc::GcHashMap,
+    parser::Source,
+    typed::Either2,
+    ContextBuilder, ContextInitializer as _, FileImportResolver, IStr, ObjValue, ObjValueBuilder,
+    Result, State, Thunk, Val,
+};
+use jrsonnet_gcmodule::Trace;
+use jrsonnet_stdlib::{ContextInitializer, PathResolver};
+use pyo3::{prelude::*, types::PyDict};
+use std::{any::Any, collections::BTreeMap, path::PathBuf};
+
+/// Evaluate jsonnet code or file with the chainql standard library.
+///
+/// `cql` object is available the same way as in the chainql CLI, as a global
+/// and as `std.extVar('cql')`. String is treated as a code snippet, path-like
+/// object as a file to import, so its relative imports are resolved from its directory.
+#[pyfunction]
+#[pyo3(signature = (code, ext_vars=None, tla=None, import_paths=None))]
+pub fn evaluate(
+    py: Python<'_>,
+    code: &Bound<'_, PyAny>,
+    ext_vars: Option<Py<PyDict>>,
+    tla: Option<Py<PyDict>>,
+    import_paths: Option<Vec<PathBuf>>,
+) -> PyResult<PyObject> {
+    let code = if let Ok(code) = code.extract::<String>() {
+        Code::Snippet(code)
+    } else {
+        let path = code
+            .extract::<PathBuf>()
+            .map_err(|err| type_error(py, "code should be a string or a path", err))?;
+
+        Code::File(path)
+    };
+
+    execute_jsonnet(py, move |_| {
+        let (ext_vars, tla) = Python::with_gil(|py| {
+            let ext_vars = ext_vars.map(|d| pydict_to_vars(d.bind(py))).transpose()?;
+            let tla = tla.map(|d| pydict_to_vars(d.bind(py))).transpose()?;
+            PyResult::Ok((ext_vars.unwrap_or_default(), tla.unwrap_or_default()))
+        })?;
+
+        let cql = cql();
+        let std = ContextInitializer::new(PathResolver::new_cwd_fallback());
+        std.add_ext_var("cql".into(), Val::Obj(cql.clone()));
+        for (name, value) in ext_vars {
+            std.add_ext_var(name, value);
+        }
+
+        let mut state = State::builder();
+        state
+            .context_initializer(CqlContextInitializer { std, cql })
+            .import_resolver(FileImportResolver::new(import_paths.unwrap_or_default()));
+        let state = state.build();
+
+        let tla = tla
+            .into_iter()
+            .map(|(name, value)| (name, TlaArg::Val(value)))
+            .collect::<GcHashMap<_, _>>();
+
+        let value = match code {
+            Code::Snippet(code) => state.evaluate_snippet("<evaluate>", code),
+            Code::File(path) => state.import(path),
+        };
+
+        let value = value
+            .and_then(|value| apply_tla(state.clone(), &tla, value))
+            .map_err(jsonnet_error)?;
+
+        jsonnet_to_pyobject(value)
+    })
+}
+
+/// Jsonnet code, passed to `evaluate`.
+enum Code {
+    Snippet(String),
+    File(PathBuf),
+}
+
+/// Standard library with `cql` bound as a global, same as in the chainql CLI.
+#[derive(Trace)]
+struct CqlContextInitializer {
+    std: ContextInitializer,
+    cql: ObjValue,
+}
+
+impl jrsonnet_evaluator::ContextInitializer for CqlContextInitializer {
+    fn reserve_vars(&self) -> usize {
+        self.std.reserve_vars() + 1
+    }
+
+    fn populate(&self, for_file: Source, builder: &mut ContextBuilder) {
+        self.std.populate(for_file, builder);
+        builder.bind("cql", Thunk::evaluated(Val::Obj(self.cql.clone())));
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn pydict_to_vars(dict: &Bound<'_, PyDict>) -> PyResult<Vec<(IStr, Val)>> {
+    let py = dict.py();
+
+    dict.iter()
+        .map(|(key, value)| {
+            let key = key
+                .extract::<String>()
+                .map_err(|err| type_error(py, "variable name should be a string", err))?;
+
+            let value = py_to_jsonnet(py, value)
+                .map_err(|err| type_error(py, format!("unsupported value type at '{key}'"), err))?;
+
+            Ok((key.into(), value))
+        })
+        .collect()
+}
+
+/// The `cql` object of the chainql CLI.
+fn cql() -> ObjValue {
+    let mut cql = ObjValueBuilder::new();
+
+    cql.method("chain", cql_chain {});
+    cql.method("dump", cql_dump {});
+    cql.method("ss58", cql_ss58 {});
+    cql.method("ss58Encode", cql_ss58_encode {});
+    cql.method("twox128", cql_twox128 {});
+    cql.method("keccak256", cql_keccak256 {});
+    cql.method("ethEncode", cql_eth_encode {});
+
+    cql.build()
+}
+
+#[builtin]
+fn cql_chain(url: String, opts: Option<ChainOpts>) -> Result<ObjValue> {
+    chainql_core::chain(url, opts, cancellation_notifier())
+}
+
+#[builtin]
+fn cql_dump(
+    meta: Either2<ObjValue, Hex>,
+    data: BTreeMap<Hex, Hex>,
+    opts: Option<ChainOpts>,
+) -> Result<ObjValue> {
+    chainql_core::builtin_dump(meta, data, opts)
+}
+
+#[builtin]
+fn cql_ss58(v: IStr) -> Result<Hex> {
+    chainql_core::builtin_ss58(v)
+}
+
+#[builtin]
+fn cql_ss58_encode(raw: Hex, format: Option<chainql_core::address::Ss58Format>) -> Result<String> {
+    chainql_core::builtin_ss58_encode(raw, format).map(|encoded| encoded.to_string())
+}
+
+#[builtin]
+fn cql_twox128(data: Hex) -> Hex {
+    chainql_core::builtin_twox128(data)
+}
+
+#[builtin]
+fn cql_keccak256(data: Hex) -> Hex {
+    chainql_core::builtin_keccak256(data)
+}
+
+#[builtin]
+fn cql_eth_encode(address: Hex) -> Result<String> {
+    chainql_core::ethereum::builtin_eth_encode(address)
+}
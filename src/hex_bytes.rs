@@ -0,0 +1,90 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::value_error;
+use either::Either;
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyMemoryView, PyString},
+};
+
+/// Bytes decoded from the chain, shown as `0x`-prefixed hex string
+#[pyclass(frozen, eq, hash, str, module = "chainql.hex")]
+#[derive(Clone, Hash, PartialEq, Eq)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl HexBytes {
+    /// Parse `0x`-prefixed hex string, which chainql uses for byte arrays.
+    pub fn parse(hex: &str) -> Option<Self> {
+        let digits = hex.strip_prefix("0x")?;
+        if digits.len() % 2 != 0 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        chainql_core::hex::from_hex(hex).ok().map(Self)
+    }
+
+    /// Copy bytes of the buffer-protocol object, [`HexBytes`] or `0x`-prefixed hex string.
+    pub fn extract(obj: &Bound<'_, PyAny>) -> PyResult<Self> {
+        if let Ok(bytes) = obj.downcast::<PyBytes>() {
+            Ok(Self(bytes.as_bytes().to_vec()))
+        } else if let Ok(hex) = obj.downcast::<HexBytes>() {
+            Ok(hex.get().clone())
+        } else if let Ok(hex) = obj.downcast::<PyString>() {
+            let hex = hex.to_cow()?;
+            Self::parse(&hex)
+                .ok_or_else(|| PyValueError::new_err(format!("invalid hex string '{hex}'")))
+        } else {
+            let bytes = PyMemoryView::from(obj)?.call_method0("tobytes")?;
+            Ok(Self(bytes.downcast::<PyBytes>()?.as_bytes().to_vec()))
+        }
+    }
+}
+
+#[pymethods]
+impl HexBytes {
+    #[new]
+    fn new(data: Either<Vec<u8>, String>) -> PyResult<Self> {
+        match data {
+            Either::Left(bytes) => Ok(Self(bytes)),
+            Either::Right(hex) => chainql_core::hex::from_hex(&hex)
+                .map(Self)
+                .map_err(value_error),
+        }
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.0)
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("HexBytes('{self}')")
+    }
+
+    /// Hex representation without `0x` prefix, same as `bytes.hex()`
+    fn hex(&self) -> String {
+        self.to_string().split_off(2)
+    }
+}
+
+impl std::fmt::Display for HexBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&chainql_core::hex::to_hex(&self.0))
+    }
+}
@@ -13,105 +13,367 @@
 // limitations under the License.
 
 use crate::{
-    jsonnet_py::{jsonnet_to_py, py_to_jsonnet, pylist_to_jsonnet},
-    jsonnet_tokio::execute_jsonnet,
+    columnar::{storage_map_to_batch, write_parquet},
+    diff::{changes_to_py, diff_objects},
+    jsonnet_py::{
+        jsonnet_to_py, jsonnet_to_py_at, jsonnet_to_pyobject, jsonnet_to_pyobject_at,
+        py_to_jsonnet, pydict_to_jsonnet, pylist_to_jsonnet, PlainValue,
+    },
+    jsonnet_tokio::{execute_jsonnet, spawn_jsonnet, Local},
+    manifest::{manifest, stream_json, ManifestFormat, Sink, DEFAULT_INDENT},
     utils::{jsonnet_error, type_error},
+    value_type::ValueOrigin,
+};
+use arrow::pyarrow::ToPyArrow;
+use either::Either;
+use jrsonnet_evaluator::{
+    function::{ArgsLike, BuiltinParam, CallLocation, FuncVal},
+    val::ArrValue,
+    Context, ContextBuilder, IStr, ObjValue, Thunk, Val,
 };
 use pyo3::{
-    exceptions::{PyKeyError, PyTypeError},
+    exceptions::{PyIndexError, PyKeyError, PyStopAsyncIteration, PyTypeError, PyValueError},
     prelude::*,
-    types::PyTuple,
+    types::{PyDict, PyList, PySlice, PySliceIndices, PyString, PyTuple},
+};
+use pyo3_async_runtimes::tokio::future_into_py;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
 };
-use std::cell::RefCell;
 
 /// TODO
-#[pyclass(unsendable, mapping)]
+#[pyclass(mapping, subclass, module = "chainql")]
 #[derive(Clone)]
-pub struct JsonnetObject(pub jrsonnet_evaluator::ObjValue);
+pub struct JsonnetObject(
+    pub Arc<Local<jrsonnet_evaluator::ObjValue>>,
+    pub ValueOrigin,
+);
+
+impl JsonnetObject {
+    pub fn new(obj: jrsonnet_evaluator::ObjValue) -> Self {
+        Self(Arc::new(Local::new(obj)), ValueOrigin::default())
+    }
+
+    /// Storage object, which values are typed by its `_meta`.
+    pub fn storage(obj: jrsonnet_evaluator::ObjValue) -> Self {
+        let obj = Arc::new(Local::new(obj));
+        Self(Arc::clone(&obj), ValueOrigin::storage(obj))
+    }
+
+    /// Report errors of the object at the `origin` path.
+    pub fn at(self, origin: ValueOrigin) -> Self {
+        Self(self.0, origin)
+    }
+
+    fn fields(&self, include_hidden: bool) -> Vec<String> {
+        self.0
+            .fields_ex(include_hidden, true)
+            .into_iter()
+            .map(|key| key.as_str().to_owned())
+            .collect()
+    }
+
+    fn field(&self, key: &str) -> PyResult<jrsonnet_evaluator::Val> {
+        self.0
+            .get(key.into())
+            .map_err(|err| self.field_origin(key).error(err))?
+            .ok_or_else(|| PyKeyError::new_err(key.to_owned()))
+    }
+
+    fn field_origin(&self, key: &str) -> ValueOrigin {
+        self.1.join(Either::Left(key.to_owned()))
+    }
+
+    /// Field value as python object, which errors are reported at the field path.
+    fn field_py(&self, key: &str) -> PyResult<PyObject> {
+        jsonnet_to_pyobject_at(self.field(key)?, self.field_origin(key))
+    }
+}
 
 #[pymethods]
 impl JsonnetObject {
-    fn __len__(&self) -> usize {
-        self.0.len()
+    fn __len__(&self, py: Python<'_>) -> PyResult<usize> {
+        let obj = self.clone();
+        execute_jsonnet(py, move |_| Ok(obj.0.len()))
     }
 
-    fn __contains__(&self, key: &Bound<'_, PyAny>) -> bool {
-        key.extract::<&str>()
-            .map(|key| self.0.has_field(key.into()))
-            .unwrap_or(false)
+    fn __eq__(&self, py: Python<'_>, other: PyObject) -> PyResult<PyObject> {
+        let obj = self.clone();
+        equals_py(py, move || Val::Obj(ObjValue::clone(&obj.0)), other)
     }
 
-    fn __getattr__<'py>(
-        &self,
-        py: Python<'py>,
-        name: &Bound<'_, PyAny>,
-    ) -> PyResult<Bound<'py, PyAny>> {
-        execute_jsonnet(|_| {
-            let key = name
-                .extract::<&str>()
-                .map_err(|err| type_error(py, "key should be a string", err))?;
-
-            let value = self
-                .0
-                .get(key.into())
-                .map_err(jsonnet_error)?
-                .ok_or_else(|| PyKeyError::new_err(key.to_owned()))?;
+    /// Objects are lazy and may hold the whole chain state, so hashing them
+    /// is too expensive and they are not hashable.
+    #[classattr]
+    const __hash__: Option<PyObject> = None;
 
-            jsonnet_to_py(py, value)
-        })
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        let obj = self.clone();
+        let (keys, len) = execute_jsonnet(py, move |_| {
+            let keys = obj.fields(false);
+            let len = keys.len();
+            Ok((keys.into_iter().take(REPR_ITEMS).collect::<Vec<_>>(), len))
+        })?;
+
+        let keys = keys
+            .into_iter()
+            .map(|key| Ok(PyString::new(py, &key).repr()?.to_string()))
+            .collect::<PyResult<Vec<_>>>()?;
+
+        Ok(format!(
+            "JsonnetObject(keys=[{}], len={len})",
+            truncated(keys, len)
+        ))
     }
 
-    fn __getitem__<'py>(
-        &self,
-        py: Python<'py>,
-        key: &Bound<'_, PyAny>,
-    ) -> PyResult<Bound<'py, PyAny>> {
+    fn __contains__(&self, py: Python<'_>, key: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Ok(key) = key.extract::<String>() else {
+            return Ok(false);
+        };
+
+        let obj = self.clone();
+        execute_jsonnet(py, move |_| Ok(obj.0.has_field(key.as_str().into())))
+    }
+
+    fn __getattr__(&self, py: Python<'_>, name: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+        let key = name
+            .extract::<String>()
+            .map_err(|err| type_error(py, "key should be a string", err))?;
+
+        let obj = self.clone();
+        execute_jsonnet(py, move |_| obj.field_py(&key))
+    }
+
+    fn __getitem__(&self, py: Python<'_>, key: &Bound<'_, PyAny>) -> PyResult<PyObject> {
         self.__getattr__(py, key)
     }
 
+    /// Get field value without blocking the event loop
+    fn aget<'py>(&self, py: Python<'py>, key: &Bound<'_, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+        let key = key
+            .extract::<String>()
+            .map_err(|err| type_error(py, "key should be a string", err))?;
+
+        let obj = self.clone();
+        future_into_py(py, spawn_jsonnet(move |_| obj.field_py(&key)))
+    }
+
     #[pyo3(signature = (include_hidden=false))]
-    fn keys(&self, include_hidden: bool) -> JsonnetObjectKeys {
-        JsonnetObjectKeys {
-            iter: RefCell::new(Box::new(self.0.fields_ex(include_hidden, true).into_iter())),
-        }
+    fn keys(&self, py: Python<'_>, include_hidden: bool) -> PyResult<JsonnetObjectKeys> {
+        let obj = self.clone();
+        let keys = execute_jsonnet(py, move |_| Ok(obj.fields(include_hidden)))?;
+
+        Ok(JsonnetObjectKeys {
+            iter: Mutex::new(keys.into_iter()),
+        })
     }
 
     #[pyo3(signature = (include_hidden=false))]
-    fn values(&self, include_hidden: bool) -> JsonnetObjectValues {
-        JsonnetObjectValues {
+    fn values(&self, py: Python<'_>, include_hidden: bool) -> PyResult<JsonnetObjectValues> {
+        let obj = self.clone();
+        let keys = execute_jsonnet(py, move |_| Ok(obj.fields(include_hidden)))?;
+
+        Ok(JsonnetObjectValues {
+            obj: self.clone(),
+            iter: Mutex::new(keys.into_iter()),
+        })
+    }
+
+    #[pyo3(signature = (include_hidden=false))]
+    fn items(&self, py: Python<'_>, include_hidden: bool) -> PyResult<JsonnetObjectItems> {
+        let obj = self.clone();
+        let keys = execute_jsonnet(py, move |_| Ok(obj.fields(include_hidden)))?;
+
+        Ok(JsonnetObjectItems {
+            obj: self.clone(),
+            iter: Mutex::new(keys.into_iter()),
+        })
+    }
+
+    fn __aiter__(&self) -> JsonnetObjectAsyncIter {
+        JsonnetObjectAsyncIter {
             obj: self.clone(),
-            iter: RefCell::new(Box::new(self.0.fields_ex(include_hidden, true).into_iter())),
+            include_hidden: false,
+            with_values: false,
+            iter: Arc::default(),
         }
     }
 
+    /// Asynchronously iterate over `(key, value)` pairs
     #[pyo3(signature = (include_hidden=false))]
-    fn items(&self, include_hidden: bool) -> JsonnetObjectItems {
-        JsonnetObjectItems {
+    fn aitems(&self, include_hidden: bool) -> JsonnetObjectAsyncIter {
+        JsonnetObjectAsyncIter {
             obj: self.clone(),
-            iter: RefCell::new(Box::new(self.0.fields_ex(include_hidden, true).into_iter())),
+            include_hidden,
+            with_values: true,
+            iter: Arc::default(),
         }
     }
 
     #[pyo3(signature = (minified=true))]
-    fn manifest_json(&self, minified: bool) -> PyResult<String> {
-        execute_jsonnet(|_| {
-            let preserve_order = true;
-            let fmt = if minified {
-                jrsonnet_evaluator::manifest::JsonFormat::minify(preserve_order)
-            } else {
-                jrsonnet_evaluator::manifest::JsonFormat::cli(2, preserve_order)
-            };
-
-            jrsonnet_evaluator::Val::Obj(self.0.clone())
-                .manifest(fmt)
-                .map_err(jsonnet_error)
+    fn manifest_json(&self, py: Python<'_>, minified: bool) -> PyResult<String> {
+        let indent = if minified { 0 } else { DEFAULT_INDENT };
+        self.manifest(py, ManifestFormat::Json, Some(indent), false)
+    }
+
+    /// Serialize to `format`, which is one of `json`, `yaml`, `toml` or `ini`
+    #[pyo3(signature = (format=ManifestFormat::Json, indent=None, sort_keys=false))]
+    fn manifest(
+        &self,
+        py: Python<'_>,
+        format: ManifestFormat,
+        indent: Option<usize>,
+        sort_keys: bool,
+    ) -> PyResult<String> {
+        let indent = indent.unwrap_or_else(|| format.default_indent());
+        let obj = self.clone();
+        execute_jsonnet(py, move |_| {
+            manifest(Val::Obj(ObjValue::clone(&obj.0)), format, indent, sort_keys)
+        })
+        .map_err(|err| self.1.path.attach(err))
+    }
+
+    #[pyo3(signature = (indent=2, sort_keys=false))]
+    fn manifest_yaml(&self, py: Python<'_>, indent: usize, sort_keys: bool) -> PyResult<String> {
+        self.manifest(py, ManifestFormat::Yaml, Some(indent), sort_keys)
+    }
+
+    #[pyo3(signature = (indent=2, sort_keys=false))]
+    fn manifest_toml(&self, py: Python<'_>, indent: usize, sort_keys: bool) -> PyResult<String> {
+        self.manifest(py, ManifestFormat::Toml, Some(indent), sort_keys)
+    }
+
+    #[pyo3(signature = (sort_keys=false))]
+    fn manifest_ini(&self, py: Python<'_>, sort_keys: bool) -> PyResult<String> {
+        self.manifest(py, ManifestFormat::Ini, None, sort_keys)
+    }
+
+    /// Write JSON to the path or file object, evaluating one field at a time.
+    ///
+    /// With `ndjson`, every field is written on its own line.
+    #[pyo3(signature = (target, minified=true, ndjson=false, sort_keys=false))]
+    fn manifest_json_to(
+        &self,
+        py: Python<'_>,
+        target: &Bound<'_, PyAny>,
+        minified: bool,
+        ndjson: bool,
+        sort_keys: bool,
+    ) -> PyResult<()> {
+        let mut sink = Sink::new(target)?;
+        let indent = if minified { 0 } else { DEFAULT_INDENT };
+
+        let obj = self.clone();
+        execute_jsonnet(py, move |_| {
+            let value = Val::Obj(ObjValue::clone(&obj.0));
+            stream_json(value, &mut sink, indent, ndjson, sort_keys)?;
+            sink.flush()
+        })
+        .map_err(|err| self.1.path.attach(err))
+    }
+
+    /// Convert to plain python data, nested up to `depth` levels.
+    ///
+    /// With `hex_bytes`, byte arrays are returned as `chainql.hex.HexBytes`,
+    /// by default it is set by `with_hex_bytes`.
+    #[pyo3(signature = (depth=None, include_hidden=false, hex_bytes=None))]
+    fn to_python(
+        &self,
+        py: Python<'_>,
+        depth: Option<usize>,
+        include_hidden: bool,
+        hex_bytes: Option<bool>,
+    ) -> PyResult<PyObject> {
+        let origin = self.1.with_hex_bytes(hex_bytes.unwrap_or(self.1.hex_bytes));
+        let obj = self.clone();
+        execute_jsonnet(py, move |_| {
+            let value = jrsonnet_evaluator::Val::Obj(jrsonnet_evaluator::ObjValue::clone(&obj.0));
+            let ty = origin.hex_bytes.then(|| origin.value_type()).flatten();
+            let value =
+                PlainValue::evaluate(value, depth, include_hidden, ty).map_err(jsonnet_error)?;
+
+            Python::with_gil(|py| value.into_py(py).map(Bound::unbind))
         })
+        .map_err(|err| self.1.path.attach(err))
+    }
+
+    /// Same object, which returns byte arrays as `chainql.hex.HexBytes`.
+    ///
+    /// Byte arrays are found by the runtime metadata, so only the values read
+    /// from the block state or the storage dump are converted.
+    #[pyo3(signature = (enabled=true))]
+    fn with_hex_bytes(&self, enabled: bool) -> Self {
+        Self(Arc::clone(&self.0), self.1.with_hex_bytes(enabled))
+    }
+
+    /// Flatten storage map into `pyarrow.RecordBatch`.
+    ///
+    /// Struct values are split into columns by fields and nested structs
+    /// into `outer.inner` columns, u128 balances are kept exact
+    /// as `decimal128(38, 0)` or string columns.
+    fn to_arrow(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let obj = self.clone();
+        let batch = execute_jsonnet(py, move |_| storage_map_to_batch(&obj.0))?;
+
+        batch.to_pyarrow(py)
+    }
+
+    /// Write storage map to the parquet file, same columns as `to_arrow`.
+    fn to_parquet(&self, py: Python<'_>, path: PathBuf) -> PyResult<()> {
+        let obj = self.clone();
+        execute_jsonnet(py, move |_| {
+            let batch = storage_map_to_batch(&obj.0)?;
+            write_parquet(&batch, &path)
+        })
+    }
+
+    /// Difference with `other`, e.g. two dumps of the same chain.
+    ///
+    /// Nested objects are compared field by field, other values as a whole.
+    /// Result is `{"added": {path: new}, "removed": {path: old}, "changed": {path: (old, new)}}`.
+    fn diff(&self, py: Python<'_>, other: JsonnetObject) -> PyResult<Py<PyDict>> {
+        let obj = self.clone();
+        execute_jsonnet(py, move |_| {
+            let mut changes = Vec::new();
+            diff_objects(&obj.0, &other.0, &mut Vec::new(), &mut changes)?;
+
+            Python::with_gil(|py| changes_to_py(py, changes).map(Bound::unbind))
+        })
+    }
+
+    /// Create object from python dictionary
+    #[staticmethod]
+    fn from_python(py: Python<'_>, value: Py<PyDict>) -> PyResult<Self> {
+        execute_jsonnet(py, move |_| {
+            let obj = Python::with_gil(|py| pydict_to_jsonnet(py, value.into_bound(py)))?;
+            Ok(Self::new(obj))
+        })
+    }
+
+    /// Pickle evaluated fields, functions can not be pickled.
+    fn __reduce__<'py>(&self, py: Python<'py>) -> PyResult<(Bound<'py, PyAny>, (PyObject,))> {
+        let value = self.to_python(py, None, false, Some(false))?;
+        let from_python = py.get_type::<JsonnetObject>().getattr("from_python")?;
+
+        Ok((from_python, (value,)))
+    }
+
+    /// Jsonnet values are immutable, so copies share the value.
+    fn __copy__(&self) -> Self {
+        self.clone()
+    }
+
+    fn __deepcopy__(&self, _memo: &Bound<'_, PyAny>) -> Self {
+        self.clone()
     }
 }
 
-#[pyclass(unsendable)]
+#[pyclass(frozen, module = "chainql")]
 pub struct JsonnetObjectKeys {
-    iter: RefCell<Box<dyn Iterator<Item = jrsonnet_evaluator::IStr>>>,
+    iter: Mutex<std::vec::IntoIter<String>>,
 }
 
 #[pymethods]
@@ -120,18 +382,18 @@ impl JsonnetObjectKeys {
         slf
     }
 
-    fn __next__(slf: PyRefMut<'_, Self>) -> Option<String> {
-        slf.iter
-            .borrow_mut()
+    fn __next__(&self) -> Option<String> {
+        self.iter
+            .lock()
+            .expect("iterator lock should not be poisoned")
             .next()
-            .map(|key| key.as_str().to_owned())
     }
 }
 
-#[pyclass(unsendable)]
+#[pyclass(frozen, module = "chainql")]
 pub struct JsonnetObjectValues {
     obj: JsonnetObject,
-    iter: RefCell<Box<dyn Iterator<Item = jrsonnet_evaluator::IStr>>>,
+    iter: Mutex<std::vec::IntoIter<String>>,
 }
 
 #[pymethods]
@@ -140,30 +402,25 @@ impl JsonnetObjectValues {
         slf
     }
 
-    fn __next__<'py>(
-        slf: PyRefMut<'py, Self>,
-        py: Python<'py>,
-    ) -> PyResult<Option<Bound<'py, PyAny>>> {
-        execute_jsonnet(|_| {
-            let Some(key) = slf.iter.borrow_mut().next() else {
-                return Ok(None);
-            };
-
-            let obj = &slf.obj.0;
-            let value = obj
-                .get(key)
-                .map_err(jsonnet_error)?
-                .expect("iterating over keys, field exists");
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        let Some(key) = self
+            .iter
+            .lock()
+            .expect("iterator lock should not be poisoned")
+            .next()
+        else {
+            return Ok(None);
+        };
 
-            jsonnet_to_py(py, value).map(Some)
-        })
+        let obj = self.obj.clone();
+        execute_jsonnet(py, move |_| obj.field_py(&key).map(Some))
     }
 }
 
-#[pyclass(unsendable)]
+#[pyclass(frozen, module = "chainql")]
 pub struct JsonnetObjectItems {
     obj: JsonnetObject,
-    iter: RefCell<Box<dyn Iterator<Item = jrsonnet_evaluator::IStr>>>,
+    iter: Mutex<std::vec::IntoIter<String>>,
 }
 
 #[pymethods]
@@ -172,45 +429,192 @@ impl JsonnetObjectItems {
         slf
     }
 
-    fn __next__<'py>(
-        slf: PyRefMut<'py, Self>,
-        py: Python<'py>,
-    ) -> PyResult<Option<(String, Bound<'py, PyAny>)>> {
-        execute_jsonnet(|_| {
-            let Some(key) = slf.iter.borrow_mut().next() else {
-                return Ok(None);
-            };
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<(String, PyObject)>> {
+        let Some(key) = self
+            .iter
+            .lock()
+            .expect("iterator lock should not be poisoned")
+            .next()
+        else {
+            return Ok(None);
+        };
 
-            let obj = &slf.obj.0;
-            let value = obj
-                .get(key.clone())
-                .map_err(jsonnet_error)?
-                .expect("iterating over keys, field exists");
+        let obj = self.obj.clone();
+        execute_jsonnet(py, move |_| {
+            let value = obj.field_py(&key)?;
+            Ok(Some((key, value)))
+        })
+    }
+}
 
-            let pykey = key.as_str().to_string();
-            let pyvalue = jsonnet_to_py(py, value)?;
+/// Asynchronous iterator over object keys or `(key, value)` pairs
+#[pyclass(module = "chainql")]
+pub struct JsonnetObjectAsyncIter {
+    obj: JsonnetObject,
+    include_hidden: bool,
+    with_values: bool,
+    /// Keys are listed lazily on the first step, to not block the event loop.
+    iter: Arc<Mutex<Option<std::vec::IntoIter<String>>>>,
+}
 
-            Ok(Some((pykey, pyvalue)))
-        })
+#[pymethods]
+impl JsonnetObjectAsyncIter {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let obj = self.obj.clone();
+        let include_hidden = self.include_hidden;
+        let with_values = self.with_values;
+        let iter = Arc::clone(&self.iter);
+
+        future_into_py(
+            py,
+            spawn_jsonnet(move |_| {
+                let key = iter
+                    .lock()
+                    .expect("iterator lock should not be poisoned")
+                    .get_or_insert_with(|| obj.fields(include_hidden).into_iter())
+                    .next()
+                    .ok_or_else(|| PyStopAsyncIteration::new_err(()))?;
+
+                let value = with_values.then(|| obj.field(&key)).transpose()?;
+
+                Python::with_gil(|py| match value {
+                    Some(value) => {
+                        let value = jsonnet_to_py_at(py, value, obj.field_origin(&key))?;
+                        Ok((key, value).into_pyobject(py)?.into_any().unbind())
+                    }
+                    None => Ok(key.into_pyobject(py)?.into_any().unbind()),
+                })
+            }),
+        )
     }
 }
 
 /// TODO
-#[pyclass(unsendable, sequence)]
+#[pyclass(sequence, module = "chainql")]
 #[derive(Clone)]
-pub struct JsonnetArray(pub jrsonnet_evaluator::val::ArrValue);
+pub struct JsonnetArray(
+    pub Arc<Local<jrsonnet_evaluator::val::ArrValue>>,
+    pub ValueOrigin,
+);
+
+impl JsonnetArray {
+    pub fn new(arr: jrsonnet_evaluator::val::ArrValue) -> Self {
+        Self(Arc::new(Local::new(arr)), ValueOrigin::default())
+    }
+
+    /// Report errors of the array at the `origin` path.
+    pub fn at(self, origin: ValueOrigin) -> Self {
+        Self(self.0, origin)
+    }
+
+    fn element(&self, idx: usize) -> PyResult<Option<PyObject>> {
+        let origin = self.1.join(Either::Right(idx));
+        self.0
+            .get(idx)
+            .map_err(|err| origin.error(err))?
+            .map(|value| jsonnet_to_pyobject_at(value, origin))
+            .transpose()
+    }
+
+    /// Resolve python index, which may be negative, to the element index.
+    fn resolve_index(&self, idx: isize) -> PyResult<usize> {
+        let len = self.0.len();
+        let idx = if idx < 0 { idx + len as isize } else { idx };
+
+        usize::try_from(idx)
+            .ok()
+            .filter(|&idx| idx < len)
+            .ok_or_else(index_out_of_range)
+    }
+
+    /// Lazy sub-array, elements are not evaluated.
+    ///
+    /// Jsonnet arrays support only positive steps, so negative
+    /// steps are applied to the reversed array. Sub-array keeps
+    /// the origin, so its elements are typed as the original ones.
+    fn slice(&self, indices: PySliceIndices) -> Self {
+        let array = jrsonnet_evaluator::val::ArrValue::clone(&self.0);
+        let len = array.len() as isize;
+
+        let (array, start, stop, step) = if indices.step < 0 {
+            let flip = |idx: isize| (len - 1 - idx).clamp(0, len) as usize;
+            (
+                array.reversed(),
+                flip(indices.start),
+                flip(indices.stop),
+                -indices.step,
+            )
+        } else {
+            let clamp = |idx: isize| idx.clamp(0, len) as usize;
+            (
+                array,
+                clamp(indices.start),
+                clamp(indices.stop),
+                indices.step,
+            )
+        };
+
+        let step = NonZeroUsize::new(step as usize).expect("slice step can not be zero");
+        let sliced = (start < stop)
+            .then(|| array.slice(Some(start), Some(stop), Some(step)))
+            .flatten()
+            .unwrap_or_else(jrsonnet_evaluator::val::ArrValue::empty);
+
+        Self(Arc::new(Local::new(sliced)), self.1.clone())
+    }
+}
 
 #[pymethods]
 impl JsonnetArray {
-    fn __len__(&self) -> usize {
-        self.0.len()
+    fn __len__(&self, py: Python<'_>) -> PyResult<usize> {
+        let array = self.clone();
+        execute_jsonnet(py, move |_| Ok(array.0.len()))
+    }
+
+    fn __eq__(&self, py: Python<'_>, other: PyObject) -> PyResult<PyObject> {
+        let array = self.clone();
+        equals_py(py, move || Val::Arr(ArrValue::clone(&array.0)), other)
+    }
+
+    /// Arrays are compared by value, which would have to be evaluated
+    /// on every hash, so they are not hashable, same as objects.
+    #[classattr]
+    const __hash__: Option<PyObject> = None;
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        let array = self.clone();
+        let (elements, len) = execute_jsonnet(py, move |_| {
+            let elements = array
+                .0
+                .iter()
+                .take(REPR_ITEMS)
+                .map(|el| el.map_err(jsonnet_error).map(preview))
+                .collect::<PyResult<Vec<_>>>()?;
+
+            Ok((elements, array.0.len()))
+        })?;
+
+        let elements = elements
+            .into_iter()
+            .map(|el| el.repr(py))
+            .collect::<PyResult<Vec<_>>>()?;
+
+        Ok(format!(
+            "JsonnetArray([{}], len={len})",
+            truncated(elements, len)
+        ))
     }
 
-    fn __contains__(&self, py: Python<'_>, object: Bound<'_, PyAny>) -> PyResult<bool> {
-        execute_jsonnet(|_| {
-            let value = py_to_jsonnet(py, object)?;
+    fn __contains__(&self, py: Python<'_>, object: PyObject) -> PyResult<bool> {
+        let array = self.clone();
+        execute_jsonnet(py, move |_| {
+            let value = Python::with_gil(|py| py_to_jsonnet(py, object.into_bound(py)))?;
 
-            for c in self.0.iter_lazy() {
+            for c in array.0.iter_lazy() {
                 let c = c.evaluate().map_err(jsonnet_error)?;
 
                 if jrsonnet_evaluator::val::equals(&value, &c).map_err(jsonnet_error)? {
@@ -222,52 +626,254 @@ impl JsonnetArray {
         })
     }
 
-    fn __getitem__<'py>(
+    fn __getitem__(&self, py: Python<'_>, key: &Bound<'_, PyAny>) -> PyResult<PyObject> {
+        if let Ok(slice) = key.downcast::<PySlice>() {
+            let len = self.__len__(py)?;
+            let indices = slice.indices(len as isize)?;
+
+            let array = self.clone();
+            return execute_jsonnet(py, move |_| {
+                let sliced = array.slice(indices);
+                Python::with_gil(|py| Ok(sliced.into_pyobject(py)?.into_any().unbind()))
+            });
+        }
+
+        let Ok(idx) = key.extract::<isize>() else {
+            return Err(PyTypeError::new_err(
+                "index should be an integer or a slice",
+            ));
+        };
+
+        let array = self.clone();
+        execute_jsonnet(py, move |_| {
+            let idx = array.resolve_index(idx)?;
+            array.element(idx)?.ok_or_else(index_out_of_range)
+        })
+    }
+
+    /// Get element without blocking the event loop
+    fn aget<'py>(&self, py: Python<'py>, key: &Bound<'_, PyAny>) -> PyResult<Bound<'py, PyAny>> {
+        let Ok(idx) = key.extract::<isize>() else {
+            return Err(PyTypeError::new_err("index should be an integer"));
+        };
+
+        let array = self.clone();
+        future_into_py(
+            py,
+            spawn_jsonnet(move |_| {
+                let idx = array.resolve_index(idx)?;
+                array.element(idx)?.ok_or_else(index_out_of_range)
+            }),
+        )
+    }
+
+    fn __reversed__(&self, py: Python<'_>) -> PyResult<JsonnetArrayIter> {
+        let array = self.clone();
+        let reversed = execute_jsonnet(py, move |_| {
+            let reversed = jrsonnet_evaluator::val::ArrValue::clone(&array.0).reversed();
+            Ok(JsonnetArray(
+                Arc::new(Local::new(reversed)),
+                array.1.clone(),
+            ))
+        })?;
+
+        Ok(reversed.__iter__())
+    }
+
+    /// Index of the first element equal to `value`
+    #[pyo3(signature = (value, start=0, stop=None))]
+    fn index(
         &self,
-        py: Python<'py>,
-        key: &Bound<'_, PyAny>,
-    ) -> PyResult<Bound<'py, PyAny>> {
-        execute_jsonnet(|_| {
-            let Ok(idx) = key.extract::<usize>() else {
-                return Err(PyTypeError::new_err("index should be a decimal"));
-            };
-
-            let Some(value) = self.0.get(idx).map_err(jsonnet_error)? else {
-                return Err(PyKeyError::new_err(idx));
-            };
-
-            jsonnet_to_py(py, value)
+        py: Python<'_>,
+        value: PyObject,
+        start: isize,
+        stop: Option<isize>,
+    ) -> PyResult<usize> {
+        let array = self.clone();
+        execute_jsonnet(py, move |_| {
+            let value = Python::with_gil(|py| py_to_jsonnet(py, value.into_bound(py)))?;
+
+            let len = array.0.len() as isize;
+            let clamp = |idx: isize| (if idx < 0 { idx + len } else { idx }).clamp(0, len) as usize;
+            let (start, stop) = (clamp(start), clamp(stop.unwrap_or(len)));
+
+            for idx in start..stop.max(start) {
+                let element = array.0.get(idx).map_err(jsonnet_error)?;
+                let element = element.expect("index should be in range");
+
+                if jrsonnet_evaluator::val::equals(&value, &element).map_err(jsonnet_error)? {
+                    return Ok(idx);
+                }
+            }
+
+            Err(PyValueError::new_err("value is not in array"))
+        })
+    }
+
+    /// Number of elements equal to `value`
+    fn count(&self, py: Python<'_>, value: PyObject) -> PyResult<usize> {
+        let array = self.clone();
+        execute_jsonnet(py, move |_| {
+            let value = Python::with_gil(|py| py_to_jsonnet(py, value.into_bound(py)))?;
+
+            let mut count = 0;
+            for element in array.0.iter() {
+                let element = element.map_err(jsonnet_error)?;
+
+                if jrsonnet_evaluator::val::equals(&value, &element).map_err(jsonnet_error)? {
+                    count += 1;
+                }
+            }
+
+            Ok(count)
         })
     }
 
     fn __iter__(&self) -> JsonnetArrayIter {
         JsonnetArrayIter {
             array: self.clone(),
-            idx: 0,
+            idx: AtomicUsize::new(0),
+        }
+    }
+
+    fn __aiter__(&self) -> JsonnetArrayAsyncIter {
+        JsonnetArrayAsyncIter {
+            array: self.clone(),
+            idx: AtomicUsize::new(0),
         }
     }
 
     #[pyo3(signature = (minified=true))]
-    fn manifest_json(&self, minified: bool) -> PyResult<String> {
-        execute_jsonnet(|_| {
-            let preserve_order = true;
-            let fmt = if minified {
-                jrsonnet_evaluator::manifest::JsonFormat::minify(preserve_order)
-            } else {
-                jrsonnet_evaluator::manifest::JsonFormat::cli(2, preserve_order)
-            };
-
-            jrsonnet_evaluator::Val::Arr(self.0.clone())
-                .manifest(fmt)
-                .map_err(jsonnet_error)
+    fn manifest_json(&self, py: Python<'_>, minified: bool) -> PyResult<String> {
+        let indent = if minified { 0 } else { DEFAULT_INDENT };
+        self.manifest(py, ManifestFormat::Json, Some(indent), false)
+    }
+
+    /// Serialize to `format`, which is one of `json`, `yaml`, `toml` or `ini`
+    #[pyo3(signature = (format=ManifestFormat::Json, indent=None, sort_keys=false))]
+    fn manifest(
+        &self,
+        py: Python<'_>,
+        format: ManifestFormat,
+        indent: Option<usize>,
+        sort_keys: bool,
+    ) -> PyResult<String> {
+        let indent = indent.unwrap_or_else(|| format.default_indent());
+        let array = self.clone();
+        execute_jsonnet(py, move |_| {
+            manifest(
+                Val::Arr(ArrValue::clone(&array.0)),
+                format,
+                indent,
+                sort_keys,
+            )
+        })
+        .map_err(|err| self.1.path.attach(err))
+    }
+
+    #[pyo3(signature = (indent=2, sort_keys=false))]
+    fn manifest_yaml(&self, py: Python<'_>, indent: usize, sort_keys: bool) -> PyResult<String> {
+        self.manifest(py, ManifestFormat::Yaml, Some(indent), sort_keys)
+    }
+
+    #[pyo3(signature = (indent=2, sort_keys=false))]
+    fn manifest_toml(&self, py: Python<'_>, indent: usize, sort_keys: bool) -> PyResult<String> {
+        self.manifest(py, ManifestFormat::Toml, Some(indent), sort_keys)
+    }
+
+    #[pyo3(signature = (sort_keys=false))]
+    fn manifest_ini(&self, py: Python<'_>, sort_keys: bool) -> PyResult<String> {
+        self.manifest(py, ManifestFormat::Ini, None, sort_keys)
+    }
+
+    /// Write JSON to the path or file object, evaluating one field at a time.
+    ///
+    /// With `ndjson`, every field is written on its own line.
+    #[pyo3(signature = (target, minified=true, ndjson=false, sort_keys=false))]
+    fn manifest_json_to(
+        &self,
+        py: Python<'_>,
+        target: &Bound<'_, PyAny>,
+        minified: bool,
+        ndjson: bool,
+        sort_keys: bool,
+    ) -> PyResult<()> {
+        let mut sink = Sink::new(target)?;
+        let indent = if minified { 0 } else { DEFAULT_INDENT };
+
+        let array = self.clone();
+        execute_jsonnet(py, move |_| {
+            let value = Val::Arr(ArrValue::clone(&array.0));
+            stream_json(value, &mut sink, indent, ndjson, sort_keys)?;
+            sink.flush()
+        })
+        .map_err(|err| self.1.path.attach(err))
+    }
+
+    /// Convert to plain python data, nested up to `depth` levels.
+    ///
+    /// With `hex_bytes`, byte arrays are returned as `chainql.hex.HexBytes`,
+    /// by default it is set by `with_hex_bytes`.
+    #[pyo3(signature = (depth=None, include_hidden=false, hex_bytes=None))]
+    fn to_python(
+        &self,
+        py: Python<'_>,
+        depth: Option<usize>,
+        include_hidden: bool,
+        hex_bytes: Option<bool>,
+    ) -> PyResult<PyObject> {
+        let origin = self.1.with_hex_bytes(hex_bytes.unwrap_or(self.1.hex_bytes));
+        let array = self.clone();
+        execute_jsonnet(py, move |_| {
+            let value =
+                jrsonnet_evaluator::Val::Arr(jrsonnet_evaluator::val::ArrValue::clone(&array.0));
+            let ty = origin.hex_bytes.then(|| origin.value_type()).flatten();
+            let value =
+                PlainValue::evaluate(value, depth, include_hidden, ty).map_err(jsonnet_error)?;
+
+            Python::with_gil(|py| value.into_py(py).map(Bound::unbind))
         })
+        .map_err(|err| self.1.path.attach(err))
+    }
+
+    /// Same array, which returns byte arrays as `chainql.hex.HexBytes`.
+    #[pyo3(signature = (enabled=true))]
+    fn with_hex_bytes(&self, enabled: bool) -> Self {
+        Self(Arc::clone(&self.0), self.1.with_hex_bytes(enabled))
+    }
+
+    /// Create array from python list
+    #[staticmethod]
+    fn from_python(py: Python<'_>, value: Py<PyList>) -> PyResult<Self> {
+        execute_jsonnet(py, move |_| {
+            let arr = Python::with_gil(|py| pylist_to_jsonnet(py, value.bind(py).iter()))?;
+            Ok(Self::new(arr.into()))
+        })
+    }
+
+    /// Pickle evaluated elements, functions can not be pickled.
+    fn __reduce__<'py>(&self, py: Python<'py>) -> PyResult<(Bound<'py, PyAny>, (PyObject,))> {
+        let value = self.to_python(py, None, false, Some(false))?;
+        let from_python = py.get_type::<JsonnetArray>().getattr("from_python")?;
+
+        Ok((from_python, (value,)))
+    }
+
+    /// Jsonnet values are immutable, so copies share the value.
+    fn __copy__(&self) -> Self {
+        self.clone()
+    }
+
+    fn __deepcopy__(&self, _memo: &Bound<'_, PyAny>) -> Self {
+        self.clone()
     }
 }
 
-#[pyclass(unsendable)]
+#[pyclass(frozen, module = "chainql")]
 pub struct JsonnetArrayIter {
     array: JsonnetArray,
-    idx: usize,
+    /// Index of the next element, taken before its evaluation
+    idx: AtomicUsize,
 }
 
 #[pymethods]
@@ -276,49 +882,338 @@ impl JsonnetArrayIter {
         slf
     }
 
-    fn __next__<'py>(
-        mut slf: PyRefMut<'py, Self>,
-        py: Python<'py>,
-    ) -> PyResult<Option<Bound<'py, PyAny>>> {
-        let slf = &mut slf;
+    fn __next__(&self, py: Python<'_>) -> PyResult<Option<PyObject>> {
+        // Index is taken before the evaluation, so concurrent calls get different elements.
+        let array = self.array.clone();
+        let idx = self.idx.fetch_add(1, Ordering::Relaxed);
 
-        execute_jsonnet(|_| {
-            slf.array
-                .0
-                .get(slf.idx)
-                .map_err(jsonnet_error)?
-                .map(|value| {
-                    let pyvalue = jsonnet_to_py(py, value)?;
-                    slf.idx += 1;
-                    Ok(pyvalue)
-                })
-                .transpose()
+        execute_jsonnet(py, move |_| array.element(idx))
+    }
+}
+
+/// Number of keys or elements shown by `repr`.
+const REPR_ITEMS: usize = 5;
+
+/// Shallow view of the value for `repr`, nested containers are not evaluated.
+enum Preview {
+    Value(Val),
+    Arr,
+    Obj,
+    Func,
+}
+
+fn preview(value: Val) -> Preview {
+    match value {
+        Val::Arr(_) => Preview::Arr,
+        Val::Obj(_) => Preview::Obj,
+        Val::Func(_) => Preview::Func,
+        value => Preview::Value(value),
+    }
+}
+
+impl Preview {
+    fn repr(self, py: Python<'_>) -> PyResult<String> {
+        Ok(match self {
+            Preview::Value(value) => jsonnet_to_py(py, value)?.repr()?.to_string(),
+            Preview::Arr => "[...]".to_owned(),
+            Preview::Obj => "{...}".to_owned(),
+            Preview::Func => "<function>".to_owned(),
         })
     }
 }
 
+fn truncated(items: Vec<String>, len: usize) -> String {
+    let mut out = items.join(", ");
+    if len > items.len() {
+        out.push_str(", ...");
+    }
+    out
+}
+
+/// Structural equality with jsonnet value or python data.
+fn equals_py(
+    py: Python<'_>,
+    value: impl FnOnce() -> Val + Send + 'static,
+    other: PyObject,
+) -> PyResult<PyObject> {
+    execute_jsonnet(py, move |_| {
+        let value = value();
+        let Ok(other) = Python::with_gil(|py| py_to_jsonnet(py, other.into_bound(py))) else {
+            return Ok(Python::with_gil(|py| py.NotImplemented()));
+        };
+
+        let equal = jrsonnet_evaluator::val::equals(&value, &other).map_err(jsonnet_error)?;
+        Ok(Python::with_gil(|py| {
+            equal.into_pyobject(py)?.to_owned().into_any().unbind()
+        }))
+    })
+}
+
+fn index_out_of_range() -> PyErr {
+    PyIndexError::new_err("array index out of range")
+}
+
+/// Asynchronous iterator over array elements
+#[pyclass(frozen, module = "chainql")]
+pub struct JsonnetArrayAsyncIter {
+    array: JsonnetArray,
+    idx: AtomicUsize,
+}
+
+#[pymethods]
+impl JsonnetArrayAsyncIter {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let array = self.array.clone();
+        let idx = self.idx.fetch_add(1, Ordering::Relaxed);
+
+        future_into_py(
+            py,
+            spawn_jsonnet(move |_| {
+                array
+                    .element(idx)?
+                    .ok_or_else(|| PyStopAsyncIteration::new_err(()))
+            }),
+        )
+    }
+}
+
 /// TODO
-#[pyclass(unsendable)]
+#[pyclass(module = "chainql")]
 #[derive(Clone)]
-pub struct JsonnetFunc(pub jrsonnet_evaluator::function::FuncVal);
+pub struct JsonnetFunc(pub Arc<Local<FuncVal>>, usize);
+
+impl JsonnetFunc {
+    pub fn new(func: FuncVal) -> Self {
+        let id = func_id(&func);
+        Self(Arc::new(Local::new(func)), id)
+    }
+
+    /// Parameter names and whether parameter has a default value.
+    fn params(&self) -> Vec<(String, bool)> {
+        fn builtin_params(params: &[BuiltinParam]) -> Vec<(String, bool)> {
+            params
+                .iter()
+                .enumerate()
+                .map(|(idx, param)| {
+                    let name = param
+                        .name()
+                        .map_or_else(|| format!("arg{idx}"), str::to_owned);
+                    (name, param.has_default())
+                })
+                .collect()
+        }
+
+        match &**self.0 {
+            FuncVal::Id => vec![("x".to_owned(), false)],
+            FuncVal::Normal(desc) => desc
+                .params
+                .iter()
+                .enumerate()
+                .map(|(idx, param)| {
+                    let name = param
+                        .0
+                        .name()
+                        .map_or_else(|| format!("arg{idx}"), |name| name.to_string());
+                    (name, param.1.is_some())
+                })
+                .collect(),
+            FuncVal::StaticBuiltin(builtin) => builtin_params(builtin.params()),
+            FuncVal::Builtin(builtin) => builtin_params(builtin.params()),
+        }
+    }
+}
 
 #[pymethods]
 impl JsonnetFunc {
-    #[pyo3(signature = (*args))]
-    fn __call__<'py>(
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
         &self,
-        py: Python<'py>,
-        args: &Bound<'_, PyTuple>,
-    ) -> PyResult<Bound<'py, PyAny>> {
-        execute_jsonnet(|_| {
-            let args = pylist_to_jsonnet(py, args.iter())?;
+        py: Python<'_>,
+        args: Py<PyTuple>,
+        kwargs: Option<Py<PyDict>>,
+    ) -> PyResult<PyObject> {
+        let func = self.clone();
+        execute_jsonnet(py, move |_| {
+            let args = Python::with_gil(|py| CallArgs::new(py, args.bind(py), kwargs))?;
 
-            let out = self
+            let out = func
                 .0
-                .evaluate_simple(&args, false)
+                .evaluate(
+                    ContextBuilder::dangerous_empty_state().build(),
+                    CallLocation::native(),
+                    &args,
+                    false,
+                )
                 .map_err(jsonnet_error)?;
 
-            jsonnet_to_py(py, out)
+            jsonnet_to_pyobject(out)
         })
     }
+
+    fn __copy__(&self) -> Self {
+        self.clone()
+    }
+
+    fn __deepcopy__(&self, _memo: &Bound<'_, PyAny>) -> Self {
+        self.clone()
+    }
+
+    /// Functions can not be compared structurally, so only the same function is equal.
+    fn __eq__(&self, other: &Bound<'_, PyAny>) -> bool {
+        other
+            .downcast::<JsonnetFunc>()
+            .is_ok_and(|other| self.1 == other.borrow().1)
+    }
+
+    fn __hash__(&self) -> u64 {
+        self.1 as u64
+    }
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        let func = self.clone();
+        let params = execute_jsonnet(py, move |_| Ok(func.params()))?;
+
+        let params = params
+            .into_iter()
+            .map(|(name, has_default)| match has_default {
+                true => format!("{name}=..."),
+                false => name,
+            })
+            .collect::<Vec<_>>();
+
+        Ok(format!("JsonnetFunc({})", params.join(", ")))
+    }
+
+    /// Signature for `inspect.signature`.
+    ///
+    /// Jsonnet default values are expressions, which may depend on other
+    /// arguments, so they are shown as `...`. Jsonnet allows required parameters
+    /// after the defaulted ones, python does not, so parameters after
+    /// the first defaulted one are keyword-only.
+    #[getter]
+    fn __signature__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let func = self.clone();
+        let params = execute_jsonnet(py, move |_| Ok(func.params()))?;
+
+        let inspect = py.import("inspect")?;
+        let parameter = inspect.getattr("Parameter")?;
+        let positional = parameter.getattr("POSITIONAL_OR_KEYWORD")?;
+        let keyword_only = parameter.getattr("KEYWORD_ONLY")?;
+
+        let mut kind = &positional;
+        let params = params
+            .into_iter()
+            .map(|(name, has_default)| {
+                let kwargs = PyDict::new(py);
+                if has_default {
+                    kwargs.set_item("default", py.Ellipsis())?;
+                }
+                let param = parameter.call((name, kind), Some(&kwargs));
+                if has_default {
+                    kind = &keyword_only;
+                }
+                param
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+
+        inspect.getattr("Signature")?.call1((params,))
+    }
+}
+
+/// Address of the function value, shared by every wrapper of the same function.
+fn func_id(func: &FuncVal) -> usize {
+    fn addr<T: ?Sized>(value: &T) -> usize {
+        (value as *const T).cast::<()>() as usize
+    }
+
+    match func {
+        FuncVal::Id => 0,
+        FuncVal::Normal(desc) => addr(&**desc),
+        FuncVal::StaticBuiltin(builtin) => addr(*builtin),
+        FuncVal::Builtin(builtin) => addr(&**builtin),
+    }
+}
+
+/// Positional and named arguments of the python call.
+struct CallArgs {
+    unnamed: Vec<Val>,
+    named: Vec<(IStr, Val)>,
+}
+
+impl CallArgs {
+    fn new(
+        py: Python<'_>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<Py<PyDict>>,
+    ) -> PyResult<Self> {
+        let unnamed = pylist_to_jsonnet(py, args.iter())?;
+
+        let named = match kwargs {
+            Some(kwargs) => pydict_to_jsonnet_args(py, kwargs.bind(py))?,
+            None => Vec::new(),
+        };
+
+        Ok(Self { unnamed, named })
+    }
+}
+
+impl ArgsLike for CallArgs {
+    fn unnamed_len(&self) -> usize {
+        self.unnamed.len()
+    }
+
+    fn unnamed_iter(
+        &self,
+        _ctx: Context,
+        _tailstrict: bool,
+        handler: &mut dyn FnMut(usize, Thunk<Val>) -> jrsonnet_evaluator::Result<()>,
+    ) -> jrsonnet_evaluator::Result<()> {
+        for (idx, arg) in self.unnamed.iter().enumerate() {
+            handler(idx, Thunk::evaluated(arg.clone()))?;
+        }
+        Ok(())
+    }
+
+    fn named_iter(
+        &self,
+        _ctx: Context,
+        _tailstrict: bool,
+        handler: &mut dyn FnMut(&IStr, Thunk<Val>) -> jrsonnet_evaluator::Result<()>,
+    ) -> jrsonnet_evaluator::Result<()> {
+        for (name, arg) in &self.named {
+            handler(name, Thunk::evaluated(arg.clone()))?;
+        }
+        Ok(())
+    }
+
+    fn named_names(&self, handler: &mut dyn FnMut(&IStr)) {
+        for (name, _) in &self.named {
+            handler(name);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.unnamed.is_empty() && self.named.is_empty()
+    }
+}
+
+fn pydict_to_jsonnet_args(
+    py: Python<'_>,
+    kwargs: &Bound<'_, PyDict>,
+) -> PyResult<Vec<(IStr, Val)>> {
+    kwargs
+        .iter()
+        .map(|(name, value)| {
+            let name = name.extract::<String>()?;
+            let value = py_to_jsonnet(py, value).map_err(|err| {
+                type_error(py, format!("unsupported type of argument '{name}'"), err)
+            })?;
+
+            Ok((name.into(), value))
+        })
+        .collect()
 }
@@ -13,38 +13,180 @@
 // limitations under the License.
 
 use crate::{
+    errors::record_python_error,
+    hex_bytes::HexBytes,
     jsonnet::{JsonnetArray, JsonnetFunc, JsonnetObject},
     utils::type_error,
+    value_type::{ValueOrigin, ValueType},
 };
+use either::Either;
 use jrsonnet_evaluator as jsonnet;
+use jrsonnet_evaluator::function::builtin::Builtin;
+use jrsonnet_evaluator::function::{ArgsLike, BuiltinParam, CallLocation, FuncVal};
 use jrsonnet_evaluator::val::NumValue;
+use jrsonnet_gcmodule::Trace;
 use pyo3::exceptions::PyValueError;
 use pyo3::{
     exceptions::PyTypeError,
     prelude::*,
-    types::{PyBool, PyCFunction, PyDict, PyList, PyNone, PySet, PyTuple},
+    types::{PyBool, PyByteArray, PyBytes, PyDict, PyList, PyMemoryView, PyNone, PySet, PyTuple},
 };
+use std::any::Any;
 
 /// Convert jsonnet value to python object.
 pub fn jsonnet_to_py(py: Python<'_>, value: jsonnet::Val) -> PyResult<Bound<'_, PyAny>> {
+    jsonnet_to_py_at(py, value, ValueOrigin::default())
+}
+
+/// Convert jsonnet value to python object, taken from `origin`.
+///
+/// Errors of the nested values are reported at its path, byte arrays are
+/// converted to [`HexBytes`] if `origin` asks for it.
+pub fn jsonnet_to_py_at(
+    py: Python<'_>,
+    value: jsonnet::Val,
+    origin: ValueOrigin,
+) -> PyResult<Bound<'_, PyAny>> {
     use jsonnet::Val::*;
 
+    if let Str(s) = &value {
+        if origin.is_hex_bytes() {
+            if let Some(hex) = HexBytes::parse(s.clone().into_flat().as_str()) {
+                return Ok(hex.into_pyobject(py)?.into_any());
+            }
+        }
+    }
+
     Ok(match value {
         Bool(b) => PyBool::new(py, b).to_owned().into_any(),
         Null => PyNone::get(py).to_owned().into_any(),
         Str(s) => s.into_flat().as_str().into_pyobject(py)?.into_any(),
         Num(num) => num.into_pyobject(py)?.into_any(),
         BigInt(bignum) => bignum.into_pyobject(py)?.into_any(),
-        Arr(arr) => JsonnetArray(arr).into_pyobject(py)?.into_any(),
-        Obj(obj) => JsonnetObject(obj).into_pyobject(py)?.into_any(),
-        Func(func) => JsonnetFunc(func).into_pyobject(py)?.into_any(),
+        Arr(arr) => JsonnetArray::new(arr)
+            .at(origin)
+            .into_pyobject(py)?
+            .into_any(),
+        Obj(obj) => JsonnetObject::new(obj)
+            .at(origin)
+            .into_pyobject(py)?
+            .into_any(),
+        Func(func) => JsonnetFunc::new(func).into_pyobject(py)?.into_any(),
     })
 }
 
+/// Convert jsonnet value to python object, holding the GIL only for the conversion.
+pub fn jsonnet_to_pyobject(value: jsonnet::Val) -> PyResult<PyObject> {
+    Python::with_gil(|py| jsonnet_to_py(py, value).map(Bound::unbind))
+}
+
+/// Same as `jsonnet_to_pyobject`, for the value taken from `origin`.
+pub fn jsonnet_to_pyobject_at(value: jsonnet::Val, origin: ValueOrigin) -> PyResult<PyObject> {
+    Python::with_gil(|py| jsonnet_to_py_at(py, value, origin).map(Bound::unbind))
+}
+
+/// Jsonnet value with evaluated containers, ready to be converted to plain python data.
+pub enum PlainValue {
+    /// Primitive value, function or container below the depth limit
+    Value(jsonnet::Val),
+    Arr(Vec<PlainValue>),
+    Obj(Vec<(String, PlainValue)>),
+    /// String, which metadata type is a byte array
+    Bytes(HexBytes),
+}
+
+impl PlainValue {
+    /// Evaluate containers of the value recursively, up to `depth` levels.
+    ///
+    /// With `ty`, byte arrays are decoded from their hex strings.
+    pub fn evaluate(
+        value: jsonnet::Val,
+        depth: Option<usize>,
+        include_hidden: bool,
+        ty: Option<ValueType>,
+    ) -> jsonnet::Result<Self> {
+        if let jsonnet::Val::Str(s) = &value {
+            let hex = ty
+                .filter(ValueType::is_bytes)
+                .and_then(|_| HexBytes::parse(s.clone().into_flat().as_str()));
+            return Ok(hex.map_or(Self::Value(value), Self::Bytes));
+        }
+
+        if depth == Some(0) {
+            return Ok(Self::Value(value));
+        }
+
+        let depth = depth.map(|depth| depth - 1);
+        let child = |segment: Either<String, usize>| ty.as_ref().and_then(|ty| ty.child(&segment));
+
+        Ok(match value {
+            jsonnet::Val::Arr(arr) => Self::Arr(
+                arr.iter()
+                    .enumerate()
+                    .map(|(idx, el)| {
+                        Self::evaluate(el?, depth, include_hidden, child(Either::Right(idx)))
+                    })
+                    .collect::<jsonnet::Result<_>>()?,
+            ),
+            jsonnet::Val::Obj(obj) => Self::Obj(
+                obj.fields_ex(include_hidden, true)
+                    .into_iter()
+                    .map(|key| {
+                        let value = obj.get(key.clone())?.expect("field should exist");
+                        let key = key.as_str().to_owned();
+                        let ty = child(Either::Left(key.clone()));
+                        Ok((key, Self::evaluate(value, depth, include_hidden, ty)?))
+                    })
+                    .collect::<jsonnet::Result<_>>()?,
+            ),
+            value => Self::Value(value),
+        })
+    }
+
+    /// Convert to python `dict`, `list` and primitive objects.
+    pub fn into_py(self, py: Python<'_>) -> PyResult<Bound<'_, PyAny>> {
+        Ok(match self {
+            Self::Value(value) => jsonnet_to_py(py, value)?,
+            Self::Bytes(hex) => hex.into_pyobject(py)?.into_any(),
+            Self::Arr(arr) => {
+                let arr = arr
+                    .into_iter()
+                    .map(|el| el.into_py(py))
+                    .collect::<PyResult<Vec<_>>>()?;
+                PyList::new(py, arr)?.into_any()
+            }
+            Self::Obj(obj) => {
+                let dict = PyDict::new(py);
+                for (key, value) in obj {
+                    dict.set_item(key, value.into_py(py)?)?;
+                }
+                dict.into_any()
+            }
+        })
+    }
+}
+
 /// Convert python object to jsonnet value.
 pub fn py_to_jsonnet(py: Python<'_>, arg: Bound<'_, PyAny>) -> PyResult<jsonnet::Val> {
     if arg.is_none() {
         Ok(jsonnet::Val::Null)
+    } else if let Ok(bytes) = arg.downcast::<PyBytes>() {
+        Ok(hex_to_jsonnet(bytes.as_bytes()))
+    } else if let Ok(bytes) = arg.downcast::<PyByteArray>() {
+        Ok(hex_to_jsonnet(&bytes.to_vec()))
+    } else if let Ok(view) = arg.downcast::<PyMemoryView>() {
+        let bytes = view.call_method0("tobytes")?;
+        Ok(hex_to_jsonnet(bytes.downcast::<PyBytes>()?.as_bytes()))
+    } else if let Ok(hex) = arg.downcast::<HexBytes>() {
+        Ok(hex_to_jsonnet(&hex.get().0))
+    } else if let Ok(obj) = arg.downcast::<JsonnetObject>() {
+        Ok(jsonnet::Val::Obj(jsonnet::ObjValue::clone(&obj.borrow().0)))
+    } else if let Ok(arr) = arg.downcast::<JsonnetArray>() {
+        Ok(jsonnet::Val::Arr(jsonnet::val::ArrValue::clone(
+            &arr.borrow().0,
+        )))
+    } else if let Ok(func) = arg.downcast::<JsonnetFunc>() {
+        Ok(jsonnet::Val::Func(FuncVal::clone(&func.borrow().0)))
     } else if let Ok(b) = arg.extract::<bool>() {
         Ok(jsonnet::Val::Bool(b))
     } else if let Ok(s) = arg.extract::<&str>() {
@@ -67,14 +209,20 @@ pub fn py_to_jsonnet(py: Python<'_>, arg: Bound<'_, PyAny>) -> PyResult<jsonnet:
     } else if let Ok(dict) = arg.extract::<Bound<'_, PyDict>>() {
         let obj = pydict_to_jsonnet(py, dict)?;
         Ok(jsonnet::Val::Obj(obj))
-    } else if let Ok(_func) = arg.extract::<Bound<'_, PyCFunction>>() {
-        Err(PyTypeError::new_err("functions are not supported"))
+    } else if arg.is_callable() {
+        let func = pycallable_to_jsonnet(py, arg)?;
+        Ok(jsonnet::Val::Func(func))
     } else {
         let ty_name = arg.get_type().name()?;
         Err(PyTypeError::new_err(format!("unsupported type {ty_name}")))
     }
 }
 
+/// Bytes in the chainql `Hex` representation.
+fn hex_to_jsonnet(bytes: &[u8]) -> jsonnet::Val {
+    jsonnet::Val::Str(chainql_core::hex::to_hex(bytes).as_str().into())
+}
+
 /// Convert list of python objects to array of jsonnet values.
 pub fn pylist_to_jsonnet<'py>(
     py: Python<'py>,
@@ -105,3 +253,130 @@ pub fn pydict_to_jsonnet(py: Python<'_>, dict: Bound<'_, PyDict>) -> PyResult<js
 
     Ok(obj.build())
 }
+
+/// Wrap python callable into jsonnet function.
+///
+/// Jsonnet parameters are taken from the callable signature, parameters with
+/// python defaults may be omitted. Arguments are passed to python as is, so
+/// callables with `*args` accept any number of them. Callables without
+/// inspectable signature (some builtins) are shown with a single parameter.
+pub fn pycallable_to_jsonnet(py: Python<'_>, callable: Bound<'_, PyAny>) -> PyResult<FuncVal> {
+    let params = callable_params(py, &callable)
+        .unwrap_or_else(|_| vec![("x".to_owned(), false)])
+        .into_iter()
+        .map(|(name, has_default)| BuiltinParam::new(Some(name.into()), has_default))
+        .collect();
+
+    let name = callable
+        .getattr("__name__")
+        .and_then(|name| name.extract())
+        .unwrap_or_else(|_| "<python>".to_owned());
+
+    Ok(FuncVal::builtin(PyCallback {
+        name,
+        params,
+        callable: callable.unbind(),
+    }))
+}
+
+/// Names of the named parameters of the callable and whether parameter has a default value.
+fn callable_params(py: Python<'_>, callable: &Bound<'_, PyAny>) -> PyResult<Vec<(String, bool)>> {
+    let inspect = py.import("inspect")?;
+    let parameter = inspect.getattr("Parameter")?;
+    let empty = parameter.getattr("empty")?;
+    let named = [
+        parameter.getattr("POSITIONAL_ONLY")?,
+        parameter.getattr("POSITIONAL_OR_KEYWORD")?,
+        parameter.getattr("KEYWORD_ONLY")?,
+    ];
+
+    let signature = inspect.call_method1("signature", (callable,))?;
+    let mut params = Vec::new();
+
+    for param in signature
+        .getattr("parameters")?
+        .call_method0("values")?
+        .try_iter()?
+    {
+        let param = param?;
+
+        let kind = param.getattr("kind")?;
+        if !named.iter().any(|p| p.eq(&kind).unwrap_or(false)) {
+            continue;
+        }
+
+        let has_default = !param.getattr("default")?.is(&empty);
+        params.push((param.getattr("name")?.extract()?, has_default));
+    }
+
+    Ok(params)
+}
+
+/// Python callable, called by jsonnet with the GIL acquired.
+///
+/// Arguments are checked by python, its exception is chained to the chainql error.
+#[derive(Trace)]
+struct PyCallback {
+    name: String,
+    #[trace(skip)]
+    params: Vec<BuiltinParam>,
+    #[trace(skip)]
+    callable: Py<PyAny>,
+}
+
+impl Builtin for PyCallback {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn params(&self) -> &[BuiltinParam] {
+        &self.params
+    }
+
+    fn call(
+        &self,
+        ctx: jsonnet::Context,
+        _loc: CallLocation<'_>,
+        args: &dyn ArgsLike,
+    ) -> jsonnet::Result<jsonnet::Val> {
+        let mut unnamed = Vec::with_capacity(args.unnamed_len());
+        args.unnamed_iter(ctx.clone(), false, &mut |_, arg| {
+            unnamed.push(arg.evaluate()?);
+            Ok(())
+        })?;
+
+        let mut named = Vec::new();
+        args.named_iter(ctx, false, &mut |name, arg| {
+            named.push((name.clone(), arg.evaluate()?));
+            Ok(())
+        })?;
+
+        Python::with_gil(|py| {
+            let args = unnamed
+                .into_iter()
+                .map(|arg| jsonnet_to_py(py, arg))
+                .collect::<PyResult<Vec<_>>>()?;
+
+            let kwargs = PyDict::new(py);
+            for (name, arg) in named {
+                kwargs.set_item(name.as_str(), jsonnet_to_py(py, arg)?)?;
+            }
+
+            let out = self
+                .callable
+                .bind(py)
+                .call(PyTuple::new(py, args)?, Some(&kwargs))?;
+
+            py_to_jsonnet(py, out)
+        })
+        .map_err(|err| {
+            let message = format!("python function {} failed: {err}", self.name);
+            record_python_error(err);
+            jsonnet::error::ErrorKind::RuntimeError(message.into()).into()
+        })
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
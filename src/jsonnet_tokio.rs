@@ -12,11 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::errors::{cancelled_error, reset_observations};
 use nix::sys::signal::{signal, SigHandler, Signal};
-use pyo3::PyResult;
-use std::sync::Arc;
-use std::sync::LazyLock;
-use tokio::sync::Notify;
+use pyo3::{exceptions::PyRuntimeError, prelude::*};
+use std::cell::Cell;
+use std::future::Future;
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, LazyLock, Mutex, MutexGuard, PoisonError};
+use std::thread::ThreadId;
+use tokio::sync::{oneshot, Notify};
 
 static RUNTIME: LazyLock<tokio::runtime::Runtime> = LazyLock::new(|| {
     tokio::runtime::Builder::new_multi_thread()
@@ -28,33 +35,321 @@ static RUNTIME: LazyLock<tokio::runtime::Runtime> = LazyLock::new(|| {
 
 static CANCELLATION_NOTIFIER: LazyLock<Arc<Notify>> = LazyLock::new(|| Arc::new(Notify::new()));
 
-struct CancellationGuard(SigHandler);
+/// Number of Ctrl-C presses, handled by cancellation guards.
+static CTRL_C_PRESSES: AtomicU64 = AtomicU64::new(0);
+
+/// Number of the job, running on the evaluator thread.
+static CURRENT_JOB: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Call, which submitted the job running on the evaluator thread.
+    static CURRENT_CALL: Cell<Option<Call>> = const { Cell::new(None) };
+}
+
+static EVALUATOR: LazyLock<Evaluator> = LazyLock::new(Evaluator::spawn);
+
+/// Number of active guards and the SIGINT handler to restore after the last one.
+static CANCELLATION_GUARDS: Mutex<(usize, Option<SigHandler>)> = Mutex::new((0, None));
+
+/// Ctrl-C state of a single call from python.
+///
+/// Every call remembers the number of Ctrl-C presses at its start, so a press
+/// cancels calls, which were running at that moment, but not the later ones.
+#[derive(Clone, Copy)]
+struct Call {
+    presses: u64,
+}
+
+impl Call {
+    fn start() -> Self {
+        Self {
+            presses: CTRL_C_PRESSES.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Whether Ctrl-C was pressed since the call started.
+    fn is_cancelled(self) -> bool {
+        CTRL_C_PRESSES.load(Ordering::SeqCst) != self.presses
+    }
+
+    /// Wait until Ctrl-C is pressed, returns immediately if it already was.
+    async fn cancelled(self) {
+        loop {
+            let notified = CANCELLATION_NOTIFIER.notified();
+            tokio::pin!(notified);
+
+            // Register as a waiter before the check, so press between them isn't missed.
+            notified.as_mut().enable();
+            if self.is_cancelled() {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+/// Handles Ctrl-C while python threads wait for the evaluator.
+///
+/// Guards may be set up from several threads at once, so the original
+/// handler is replaced by the first guard and restored by the last one.
+struct CancellationGuard {
+    call: Call,
+}
 
 impl CancellationGuard {
     fn setup() -> Self {
-        Self(unsafe { signal(Signal::SIGINT, SigHandler::Handler(Self::ctrl_c_handler)).unwrap() })
+        let mut guards = Self::guards();
+
+        if guards.0 == 0 {
+            let handler = SigHandler::Handler(Self::ctrl_c_handler);
+            guards.1 = Some(unsafe { signal(Signal::SIGINT, handler).unwrap() });
+        }
+
+        guards.0 += 1;
+
+        Self {
+            call: Call::start(),
+        }
+    }
+
+    fn guards() -> MutexGuard<'static, (usize, Option<SigHandler>)> {
+        CANCELLATION_GUARDS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 
     extern "C" fn ctrl_c_handler(_signal: core::ffi::c_int) {
-        CANCELLATION_NOTIFIER.notify_one();
+        CTRL_C_PRESSES.fetch_add(1, Ordering::SeqCst);
+
+        // Every request in flight should be interrupted, not only one of them.
+        CANCELLATION_NOTIFIER.notify_waiters();
     }
 }
 
 impl Drop for CancellationGuard {
     fn drop(&mut self) {
-        unsafe { signal(Signal::SIGINT, self.0).unwrap() };
+        let mut guards = Self::guards();
+
+        guards.0 -= 1;
+
+        if guards.0 == 0 {
+            let handler = guards.1.take().expect("previous handler should be saved");
+            unsafe { signal(Signal::SIGINT, handler).unwrap() };
+        }
+    }
+}
+
+/// Job and the call, which submitted it, if any.
+type Job = (Option<Call>, Box<dyn FnOnce() + Send>);
+
+/// Dedicated thread, which owns every jsonnet value.
+///
+/// Jsonnet values are reference counted without atomics and use thread local
+/// string interner, so all evaluation is performed on a single thread and
+/// python threads (and asyncio tasks) only submit jobs to it. This makes every
+/// python wrapper usable from any thread, while evaluation stays serialized:
+/// a long evaluation delays every other job, including ones from asyncio tasks.
+struct Evaluator {
+    thread: ThreadId,
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Evaluator {
+    fn spawn() -> Self {
+        let (jobs, queue) = mpsc::channel::<Job>();
+
+        let thread = std::thread::Builder::new()
+            .name("chainql-jsonnet-evaluator".to_owned())
+            .spawn(move || {
+                let _enter_guard = RUNTIME.enter();
+
+                for (call, job) in queue {
+                    CURRENT_CALL.set(call);
+                    CURRENT_JOB.fetch_add(1, Ordering::Relaxed);
+
+                    // Failures of the previous job are not related to this one.
+                    reset_observations();
+
+                    // Panicked job drops its result sender, which is reported to the caller.
+                    let _ = catch_unwind(AssertUnwindSafe(job));
+
+                    CURRENT_CALL.set(None);
+                }
+            })
+            .expect("failed to spawn jsonnet evaluator thread");
+
+        Self {
+            thread: thread.thread().id(),
+            jobs,
+        }
+    }
+
+    fn is_current(&self) -> bool {
+        std::thread::current().id() == self.thread
+    }
+
+    fn submit<F, T>(&self, call: Call, f: F) -> oneshot::Receiver<PyResult<T>>
+    where
+        F: FnOnce(Arc<Notify>) -> PyResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        let job = Box::new(move || {
+            let cancel = Arc::clone(&CANCELLATION_NOTIFIER);
+            let _ = tx.send(f(cancel));
+        });
+
+        self.jobs
+            .send((Some(call), job))
+            .expect("jsonnet evaluator thread should be alive");
+
+        rx
+    }
+}
+
+/// Value, which lives on the jsonnet evaluator thread.
+///
+/// `Local` can be moved and shared between threads, but the value itself
+/// is accessible only from jobs running on the evaluator thread.
+/// Dropping a `Local` on any other thread sends the value back to the evaluator.
+pub struct Local<T: 'static>(ManuallyDrop<T>);
+
+// SAFETY: inner value is accessed and dropped only on the evaluator thread.
+unsafe impl<T: 'static> Send for Local<T> {}
+unsafe impl<T: 'static> Sync for Local<T> {}
+
+impl<T: 'static> Local<T> {
+    #[track_caller]
+    pub fn new(value: T) -> Self {
+        assert!(
+            EVALUATOR.is_current(),
+            "jsonnet values should be created on the evaluator thread"
+        );
+
+        Self(ManuallyDrop::new(value))
+    }
+}
+
+impl<T: 'static> Deref for Local<T> {
+    type Target = T;
+
+    #[track_caller]
+    fn deref(&self) -> &T {
+        assert!(
+            EVALUATOR.is_current(),
+            "jsonnet values should be accessed on the evaluator thread"
+        );
+
+        &self.0
+    }
+}
+
+impl<T: 'static> Drop for Local<T> {
+    fn drop(&mut self) {
+        if EVALUATOR.is_current() {
+            // SAFETY: value is never used after drop.
+            unsafe { ManuallyDrop::drop(&mut self.0) };
+            return;
+        }
+
+        // SAFETY: value is moved out once and wrapped back into `Local`.
+        let value = unsafe { ManuallyDrop::take(&mut self.0) };
+        let value = Local(ManuallyDrop::new(value));
+
+        if let Err(mpsc::SendError(job)) =
+            EVALUATOR.jobs.send((None, Box::new(move || drop(value))))
+        {
+            // Evaluator is gone, the only safe option is to leak the value.
+            std::mem::forget(job);
+        }
     }
 }
 
+/// Whether Ctrl-C was pressed during the call, which submitted the current job.
+pub fn is_cancelled() -> bool {
+    CURRENT_CALL.get().is_some_and(Call::is_cancelled)
+}
+
+/// Number of the job, running on the evaluator thread.
+///
+/// The local endpoint attributes chainql-core requests to the current job.
+pub fn current_job() -> u64 {
+    CURRENT_JOB.load(Ordering::Relaxed)
+}
+
+/// Notifier, which wakes chainql-core requests up on Ctrl-C.
+pub fn cancellation_notifier() -> Arc<Notify> {
+    Arc::clone(&CANCELLATION_NOTIFIER)
+}
+
+/// Use the chainql tokio runtime for python awaitables.
+pub fn init_async_runtime() {
+    pyo3_async_runtimes::tokio::init_with_runtime(&RUNTIME)
+        .expect("async runtime should be initialized once");
+}
+
+/// Run future on the chainql tokio runtime and block until it is done.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    RUNTIME.block_on(future)
+}
+
+/// Run `f` on the evaluator thread and block until it is done.
+///
+/// The GIL is released while `f` is running, so `f` should
+/// acquire it only to convert values from or to python objects.
 #[inline]
 #[track_caller]
-pub fn execute_jsonnet<F, T>(f: F) -> F::Output
+pub fn execute_jsonnet<F, T>(py: Python<'_>, f: F) -> PyResult<T>
+where
+    F: FnOnce(Arc<Notify>) -> PyResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    if EVALUATOR.is_current() {
+        let cancel = Arc::clone(&CANCELLATION_NOTIFIER);
+        return py.allow_threads(|| f(cancel));
+    }
+
+    let ctrl_c = CancellationGuard::setup();
+
+    let result = EVALUATOR.submit(ctrl_c.call, f);
+    py.allow_threads(|| result.blocking_recv())
+        .unwrap_or_else(|_| Err(evaluator_panicked()))
+}
+
+/// Run `f` on the evaluator thread and return future, which resolves to the result.
+///
+/// Jobs are queued behind every job submitted earlier, so the future may wait
+/// for evaluation of other futures. Ctrl-C is handled until the future is done or dropped.
+pub fn spawn_jsonnet<F, T>(f: F) -> impl Future<Output = PyResult<T>> + Send + 'static
 where
-    F: FnOnce(Arc<Notify>) -> PyResult<T>,
+    F: FnOnce(Arc<Notify>) -> PyResult<T> + Send + 'static,
+    T: Send + 'static,
 {
-    let _ctrl_c = CancellationGuard::setup();
-    let _enter_guard = RUNTIME.enter();
+    async move {
+        let ctrl_c = CancellationGuard::setup();
+
+        let result = EVALUATOR.submit(ctrl_c.call, f);
+        result.await.unwrap_or_else(|_| Err(evaluator_panicked()))
+    }
+}
+
+/// Run `future`, which fails with `Cancelled` error on Ctrl-C.
+///
+/// Used for node requests, which are made outside of jsonnet evaluation.
+pub async fn cancellable<F, T>(future: F) -> PyResult<T>
+where
+    F: Future<Output = PyResult<T>>,
+{
+    let ctrl_c = CancellationGuard::setup();
+
+    tokio::select! {
+        result = future => result,
+        () = ctrl_c.call.cancelled() => Err(cancelled_error()),
+    }
+}
 
-    let cancel = Arc::clone(&CANCELLATION_NOTIFIER);
-    f(cancel)
+fn evaluator_panicked() -> PyErr {
+    PyRuntimeError::new_err("jsonnet evaluation panicked")
 }
@@ -13,13 +13,26 @@
 // limitations under the License.
 
 mod address;
+mod cache;
 mod chain;
+mod child_trie;
+mod columnar;
+mod diff;
+mod errors;
+mod evaluate;
+mod hex_bytes;
 mod jsonnet;
 mod jsonnet_py;
 mod jsonnet_tokio;
+mod manifest;
+mod node;
+mod raw_state;
+mod replay;
+mod rpc;
 mod ss58;
 mod ss58_registry;
 mod utils;
+mod value_type;
 
 use pyo3::prelude::*;
 use utils::value_error;
@@ -36,13 +49,19 @@ mod chainql {
             .install()
             .unwrap();
 
+        crate::jsonnet_tokio::init_async_runtime();
+
         Ok(())
     }
 
     #[pymodule_export]
-    use crate::chain::dump;
+    use crate::chain::{dump, dump_from_chain_spec, dump_from_snapshot};
+    #[pymodule_export]
+    use crate::chain::{AsyncChain, BlockRef, BlockState, Chain, ChainOpts, DumpState};
+    #[pymodule_export]
+    use crate::child_trie::{ChildTrie, ChildTries};
     #[pymodule_export]
-    use crate::chain::{Chain, ChainOpts};
+    use crate::evaluate::evaluate;
     #[pymodule_export]
     use crate::jsonnet::{JsonnetArray, JsonnetFunc, JsonnetObject};
 
@@ -56,6 +75,15 @@ mod chainql {
         use crate::ss58_registry::Ss58AccountFormat;
     }
 
+    #[pymodule]
+    mod errors {
+        #[pymodule_export]
+        use crate::errors::{
+            Cancelled, ChainqlError, ConnectionError, EvaluationError, MetadataDecodeError,
+            RpcError, StorageDecodeError,
+        };
+    }
+
     #[pymodule]
     mod ethereum {
         use super::*;
@@ -91,6 +119,9 @@ mod chainql {
         use super::*;
         use chainql_core::hex;
 
+        #[pymodule_export]
+        use crate::hex_bytes::HexBytes;
+
         /// Convert a hex string to bytes
         #[pyfunction]
         fn from_hex(data: &str) -> PyResult<Vec<u8>> {
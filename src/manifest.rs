@@ -0,0 +1,259 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::utils::jsonnet_error;
+use jrsonnet_evaluator::{manifest::JsonFormat, Val};
+use jrsonnet_stdlib::manifest::{IniFormat, TomlFormat, YamlFormat};
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyString},
+};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+/// Size of the buffer, after which output is passed to the python file object.
+const PY_WRITE_CHUNK: usize = 64 * 1024;
+
+/// Output format of the `manifest` method
+#[derive(Clone, Copy)]
+pub enum ManifestFormat {
+    Json,
+    Yaml,
+    Toml,
+    Ini,
+}
+
+/// Indent of the pretty-printed output.
+pub const DEFAULT_INDENT: usize = 2;
+
+impl ManifestFormat {
+    /// JSON is minified by default, same as `manifest_json`, other formats are indented.
+    pub fn default_indent(self) -> usize {
+        match self {
+            Self::Json => 0,
+            _ => DEFAULT_INDENT,
+        }
+    }
+}
+
+impl<'py> FromPyObject<'py> for ManifestFormat {
+    fn extract_bound(format: &Bound<'py, PyAny>) -> PyResult<Self> {
+        match format.extract::<&str>()? {
+            "json" => Ok(Self::Json),
+            "yaml" => Ok(Self::Yaml),
+            "toml" => Ok(Self::Toml),
+            "ini" => Ok(Self::Ini),
+            other => Err(PyValueError::new_err(format!(
+                "unknown manifest format '{other}', expected 'json', 'yaml', 'toml' or 'ini'"
+            ))),
+        }
+    }
+}
+
+/// Serialize value to the given format.
+///
+/// Fields are written in the declaration order, unless `sort_keys` is set.
+/// Zero `indent` produces minified JSON, other formats are always indented.
+pub fn manifest(
+    value: Val,
+    format: ManifestFormat,
+    indent: usize,
+    sort_keys: bool,
+) -> PyResult<String> {
+    let preserve_order = !sort_keys;
+
+    match format {
+        ManifestFormat::Json if indent == 0 => value.manifest(JsonFormat::minify(preserve_order)),
+        ManifestFormat::Json => value.manifest(JsonFormat::cli(indent, preserve_order)),
+        ManifestFormat::Yaml => value.manifest(YamlFormat::cli(indent, preserve_order)),
+        ManifestFormat::Toml => value.manifest(TomlFormat::cli(indent, preserve_order)),
+        ManifestFormat::Ini => value.manifest(IniFormat::cli(preserve_order)),
+    }
+    .map_err(jsonnet_error)
+}
+
+/// Destination of the streamed output.
+pub enum Sink {
+    File(BufWriter<File>),
+    Python {
+        file: PyObject,
+        binary: bool,
+        buffer: String,
+    },
+}
+
+impl Sink {
+    /// Open file by path, or use python file object in text or binary mode.
+    pub fn new(target: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let py = target.py();
+
+        if target.is_instance_of::<PyString>()
+            || target.is_instance(&py.import("os")?.getattr("PathLike")?)?
+        {
+            let path = target.extract::<PathBuf>()?;
+            return Ok(Self::File(BufWriter::new(File::create(path)?)));
+        }
+
+        if !target.hasattr("write")? {
+            return Err(PyValueError::new_err(
+                "target should be a path or a file object",
+            ));
+        }
+
+        let binary = !target.is_instance(&py.import("io")?.getattr("TextIOBase")?)?;
+
+        Ok(Self::Python {
+            file: target.clone().unbind(),
+            binary,
+            buffer: String::new(),
+        })
+    }
+
+    pub fn write(&mut self, chunk: &str) -> PyResult<()> {
+        match self {
+            Self::File(file) => Ok(file.write_all(chunk.as_bytes())?),
+            Self::Python { buffer, .. } => {
+                buffer.push_str(chunk);
+                if buffer.len() >= PY_WRITE_CHUNK {
+                    self.flush()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> PyResult<()> {
+        match self {
+            Self::File(file) => Ok(file.flush()?),
+            Self::Python {
+                file,
+                binary,
+                buffer,
+            } => {
+                if buffer.is_empty() {
+                    return Ok(());
+                }
+
+                let chunk = std::mem::take(buffer);
+                Python::with_gil(|py| {
+                    let chunk = match binary {
+                        true => PyBytes::new(py, chunk.as_bytes()).into_any(),
+                        false => PyString::new(py, &chunk).into_any(),
+                    };
+                    file.call_method1(py, "write", (chunk,)).map(drop)
+                })
+            }
+        }
+    }
+}
+
+/// Write value as JSON, evaluating and writing one field (or element) at a time.
+///
+/// With `ndjson`, every field is written on its own line as
+/// `{"key": ..., "value": ...}` object and every array element as is.
+pub fn stream_json(
+    value: Val,
+    sink: &mut Sink,
+    indent: usize,
+    ndjson: bool,
+    sort_keys: bool,
+) -> PyResult<()> {
+    let preserve_order = !sort_keys;
+    let to_json = |value: Val, indent: usize| match indent {
+        0 => value.manifest(JsonFormat::minify(preserve_order)),
+        indent => value.manifest(JsonFormat::cli(indent, preserve_order)),
+    };
+
+    let (open, close, items): (_, _, Box<dyn Iterator<Item = _>>) = match value {
+        Val::Obj(obj) => {
+            let fields = obj.fields_ex(false, preserve_order).into_iter();
+            let items = fields.map(move |key| {
+                let value = obj.get(key.clone())?.expect("field should exist");
+                Ok((Some(key), value))
+            });
+            ("{", "}", Box::new(items))
+        }
+        Val::Arr(arr) => {
+            let items = (0..arr.len()).map(move |idx| {
+                let value = arr.get(idx)?.expect("element should exist");
+                Ok((None, value))
+            });
+            ("[", "]", Box::new(items))
+        }
+        value => {
+            sink.write(&to_json(value, indent).map_err(jsonnet_error)?)?;
+            return sink.write(if ndjson { "\n" } else { "" });
+        }
+    };
+
+    let padding = " ".repeat(indent);
+    let (newline, separator) = match indent {
+        0 => ("", ":"),
+        _ => ("\n", ": "),
+    };
+
+    if !ndjson {
+        sink.write(open)?;
+    }
+
+    let mut empty = true;
+    for item in items {
+        let (key, value) = item.map_err(jsonnet_error)?;
+        let key = key
+            .map(|key| to_json(Val::Str(key.as_str().into()), 0))
+            .transpose()
+            .map_err(jsonnet_error)?;
+
+        if ndjson {
+            let value = to_json(value, 0).map_err(jsonnet_error)?;
+            let line = match key {
+                Some(key) => format!("{{\"key\":{key},\"value\":{value}}}\n"),
+                None => format!("{value}\n"),
+            };
+            sink.write(&line)?;
+            continue;
+        }
+
+        // JSON strings can't contain raw newlines, so nested lines are indented safely.
+        let value = to_json(value, indent)
+            .map_err(jsonnet_error)?
+            .replace('\n', &format!("\n{padding}"));
+
+        let mut chunk = String::new();
+        if !empty {
+            chunk.push(',');
+        }
+        chunk.push_str(newline);
+        chunk.push_str(&padding);
+        if let Some(key) = key {
+            chunk.push_str(&key);
+            chunk.push_str(separator);
+        }
+        chunk.push_str(&value);
+        sink.write(&chunk)?;
+
+        empty = false;
+    }
+
+    if !ndjson {
+        sink.write(if empty { "" } else { newline })?;
+        sink.write(close)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,554 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::cache::{Cache, CacheDir};
+use futures_util::{future::join_all, stream::SplitSink, SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot, OnceCell, Semaphore},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+type NodeSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+
+/// Limits of the requests, which the local endpoint sends to the node.
+///
+/// chainql-core requests keys and values in its own chunks, so the endpoint
+/// splits them into smaller requests and merges their responses back.
+#[derive(Clone, Default)]
+pub struct Limits {
+    /// Maximum number of keys in a single `state_getKeysPaged` request
+    key_page_size: Option<u32>,
+    /// Maximum number of keys in a single `state_queryStorageAt` request
+    request_batch_size: Option<usize>,
+    /// Permits for the requests in flight, shared by every connection
+    parallel: Option<Arc<Semaphore>>,
+    /// Whether values are fetched together with the keys of the storage map
+    prefetch: bool,
+}
+
+impl Limits {
+    pub fn new(
+        key_page_size: Option<u32>,
+        max_parallel_requests: Option<usize>,
+        request_batch_size: Option<usize>,
+        prefetch: bool,
+    ) -> Self {
+        Self {
+            key_page_size,
+            request_batch_size,
+            parallel: max_parallel_requests.map(|max| Arc::new(Semaphore::new(max))),
+            prefetch,
+        }
+    }
+}
+
+/// Aborts the task, when dropped.
+pub struct AbortOnDrop(pub JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Requests, which wait for the node response, by their id.
+///
+/// `None` after the node closed the connection.
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Value>>>>>;
+
+/// Websocket connection to the node.
+///
+/// Requests are sent with own ids, so that split requests don't collide
+/// with each other, and responses are routed back by them.
+struct Connection {
+    tx: tokio::sync::Mutex<NodeSink>,
+    pending: Pending,
+    next_id: AtomicU64,
+    _reader: AbortOnDrop,
+}
+
+impl Connection {
+    /// Connect to the node, messages without id (e.g. subscription notifications) are sent to `notifications`.
+    async fn open(
+        url: &str,
+        notifications: mpsc::UnboundedSender<Value>,
+    ) -> Result<Self, BoxError> {
+        let (node, _) = connect_async(url).await?;
+        let (tx, mut rx) = node.split();
+
+        let pending = Pending::new(Mutex::new(Some(HashMap::new())));
+
+        let reader = tokio::spawn({
+            let pending = pending.clone();
+            async move {
+                while let Some(Ok(message)) = rx.next().await {
+                    let Message::Text(text) = message else {
+                        continue;
+                    };
+
+                    for response in batch(text.as_str()) {
+                        let waiter = response.get("id").and_then(Value::as_u64).and_then(|id| {
+                            lock(&pending)
+                                .as_mut()
+                                .and_then(|pending| pending.remove(&id))
+                        });
+
+                        match waiter {
+                            Some(waiter) => {
+                                let _ = waiter.send(response);
+                            }
+                            None => {
+                                let _ = notifications.send(response);
+                            }
+                        }
+                    }
+                }
+
+                // Dropped senders fail every request in flight.
+                lock(&pending).take();
+            }
+        });
+
+        Ok(Self {
+            tx: tokio::sync::Mutex::new(tx),
+            pending,
+            next_id: AtomicU64::new(0),
+            _reader: AbortOnDrop(reader),
+        })
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, BoxError> {
+        if self.is_offline() {
+            return Err(format!("{method} is not cached, node is not used offline").into());
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        lock(&self.pending)
+            .as_mut()
+            .ok_or("node closed the connection")?
+            .insert(id, tx);
+
+        let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let sent = self
+            .tx
+            .lock()
+            .await
+            .send(Message::Text(request.to_string().into()))
+            .await;
+
+        if let Err(err) = sent {
+            if let Some(pending) = lock(&self.pending).as_mut() {
+                pending.remove(&id);
+            }
+            return Err(err.into());
+        }
+
+        Ok(rx.await.map_err(|_| "node closed the connection")?)
+    }
+}
+
+/// Node and the way requests to it are made, shared by every client.
+#[derive(Clone)]
+pub struct Upstream {
+    pub url: String,
+    pub limits: Limits,
+    /// Cache of the finalized responses, if `cache_dir` is set
+    pub cache: Option<Arc<CacheDir>>,
+}
+
+/// Node, shared by the requests of a single client.
+///
+/// Connection is established on the first request. In the offline mode,
+/// requests are only served from the cache and the node is never used.
+pub struct Node {
+    url: String,
+    connection: OnceCell<Connection>,
+    notifications: mpsc::UnboundedSender<Value>,
+    limits: Limits,
+    cache: Option<Arc<CacheDir>>,
+    /// Number of the last finalized block, refreshed for the newer blocks
+    finalized: Mutex<Option<u64>>,
+    /// Blocks, which were not finalized when their responses were received
+    unfinalized: Mutex<HashSet<String>>,
+    /// Values, fetched together with the keys, by the key and the block hash
+    prefetched: Mutex<HashMap<(String, String), Value>>,
+}
+
+impl Node {
+    /// Messages without id (e.g. subscription notifications) are sent to `notifications`.
+    pub fn new(upstream: Upstream, notifications: mpsc::UnboundedSender<Value>) -> Self {
+        Self {
+            url: upstream.url,
+            connection: OnceCell::new(),
+            notifications,
+            limits: upstream.limits,
+            cache: upstream.cache,
+            finalized: Mutex::default(),
+            unfinalized: Mutex::default(),
+            prefetched: Mutex::default(),
+        }
+    }
+
+    /// Send request of the client, split by the limits or served from the cache.
+    ///
+    /// Returns response object with either `result` or `error` field.
+    pub async fn request(&self, method: &str, params: Value) -> Result<Value, BoxError> {
+        let Some(cache_dir) = &self.cache else {
+            return self.fetch(method, params).await;
+        };
+        let cache = cache_dir.chain(&self.url, self.genesis()).await?;
+        let cache = cache.as_ref();
+
+        match method {
+            "state_getMetadata" => self.metadata(cache, params).await,
+            _ => self.cached(cache, method, params).await,
+        }
+    }
+
+    /// Response from the cache, or from the node, which is cached if the block is finalized.
+    async fn cached(&self, cache: &Cache, method: &str, params: Value) -> Result<Value, BoxError> {
+        if let Some(response) = cache_lookup(cache, method, &params) {
+            return Ok(response);
+        }
+
+        // Without the node, requests of the latest state get the last known response.
+        if self.is_offline() {
+            return cache
+                .latest(method, &params)
+                .ok_or_else(|| format!("{method} is not cached").into());
+        }
+
+        let response = self.fetch(method, params.clone()).await?;
+        if response.get("result").is_none() {
+            return Ok(response);
+        }
+
+        cache.store_latest(method, &params, &response);
+
+        if method == "chain_getBlockHash" {
+            if let Some(hash) = response["result"].as_str() {
+                self.is_finalized(cache, hash).await;
+            }
+        } else if let Some(hash) = Cache::block_of(method, &params) {
+            if self.is_finalized(cache, hash).await {
+                cache.store_response(hash, method, &params, &response);
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Hash of the genesis block, which identifies the chain in the cache.
+    async fn genesis(&self) -> Result<String, BoxError> {
+        let response = self.call("chain_getBlockHash", json!([0])).await?;
+        match response.get("result").and_then(Value::as_str) {
+            Some(hash) => Ok(hash.to_owned()),
+            None => Err(format!("genesis hash is not available: {response}").into()),
+        }
+    }
+
+    fn is_offline(&self) -> bool {
+        self.cache.as_ref().is_some_and(|cache| cache.offline)
+    }
+
+    /// Runtime metadata, cached by the spec version of the block.
+    async fn metadata(&self, cache: &Cache, params: Value) -> Result<Value, BoxError> {
+        let version = self
+            .cached(cache, "state_getRuntimeVersion", params.clone())
+            .await?;
+        let Some(version) = version.get("result") else {
+            return Ok(version);
+        };
+
+        if let Some(metadata) = cache.metadata(version) {
+            return Ok(metadata);
+        }
+
+        let response = self.fetch("state_getMetadata", params).await?;
+        if response.get("result").is_some() {
+            cache.store_metadata(version, &response);
+        }
+
+        Ok(response)
+    }
+
+    /// Whether the block is finalized, so that its responses never change.
+    ///
+    /// Finalized blocks are saved to the cache, failed checks are treated as unfinalized.
+    async fn is_finalized(&self, cache: &Cache, hash: &str) -> bool {
+        if cache.is_finalized(hash) {
+            return true;
+        }
+        if lock(&self.unfinalized).contains(hash) {
+            return false;
+        }
+
+        let finalized = self.check_finalized(cache, hash).await.unwrap_or(false);
+        if !finalized {
+            lock(&self.unfinalized).insert(hash.to_owned());
+        }
+
+        finalized
+    }
+
+    async fn check_finalized(&self, cache: &Cache, hash: &str) -> Result<bool, BoxError> {
+        let header_params = json!([hash]);
+        let header = self.fetch("chain_getHeader", header_params.clone()).await?;
+        let Some(number) = header_number(&header["result"]) else {
+            return Ok(false);
+        };
+
+        let known = *lock(&self.finalized);
+        let finalized = match known {
+            Some(finalized) if finalized >= number => finalized,
+            _ => {
+                let head = self.fetch("chain_getFinalizedHead", json!([])).await?;
+                let head = self
+                    .fetch("chain_getHeader", json!([head["result"]]))
+                    .await?;
+                let finalized = header_number(&head["result"]).ok_or("invalid finalized head")?;
+                *lock(&self.finalized) = Some(finalized);
+                finalized
+            }
+        };
+
+        if number > finalized {
+            return Ok(false);
+        }
+
+        let canonical = self.fetch("chain_getBlockHash", json!([number])).await?;
+        if canonical["result"].as_str() != Some(hash) {
+            return Ok(false);
+        }
+
+        cache.store_block_hash(number, hash);
+        cache.store_response(hash, "chain_getHeader", &header_params, &header);
+        Ok(true)
+    }
+
+    /// Send request to the node, split by the limits.
+    async fn fetch(&self, method: &str, params: Value) -> Result<Value, BoxError> {
+        match method {
+            "state_getKeysPaged" => self.keys_paged(params).await,
+            "state_queryStorageAt" => self.storage_at(params).await,
+            "state_getStorage" => match self.take_prefetched(&params) {
+                Some(value) => Ok(json!({ "result": value })),
+                None => self.call(method, params).await,
+            },
+            _ => self.call(method, params).await,
+        }
+    }
+
+    /// Send single request to the node.
+    async fn call(&self, method: &str, params: Value) -> Result<Value, BoxError> {
+        if self.is_offline() {
+            return Err(format!("{method} is not cached, node is not used offline").into());
+        }
+
+        let _permit = match &self.limits.parallel {
+            Some(parallel) => Some(parallel.acquire().await?),
+            None => None,
+        };
+
+        let connection = self
+            .connection
+            .get_or_try_init(|| Connection::open(&self.url, self.notifications.clone()))
+            .await?;
+
+        connection.call(method, params).await
+    }
+
+    /// `state_getKeysPaged` with `count` split into pages of at most `key_page_size`.
+    async fn keys_paged(&self, params: Value) -> Result<Value, BoxError> {
+        let Some([prefix, count, start, rest @ ..]) = params.as_array().map(Vec::as_slice) else {
+            return self.call("state_getKeysPaged", params).await;
+        };
+        let (Some(count), hash) = (count.as_u64(), rest.first()) else {
+            return self.call("state_getKeysPaged", params).await;
+        };
+
+        let page_size = self.limits.key_page_size.map_or(count, u64::from);
+        let mut keys = Vec::new();
+        let mut start = start.clone();
+
+        while (keys.len() as u64) < count {
+            let page = (count - keys.len() as u64).min(page_size);
+
+            let mut params = vec![prefix.clone(), json!(page), start];
+            params.extend(hash.cloned());
+
+            let response = self
+                .call("state_getKeysPaged", Value::Array(params))
+                .await?;
+            let Some(chunk) = response.get("result").and_then(Value::as_array) else {
+                return Ok(response);
+            };
+
+            let done = (chunk.len() as u64) < page;
+            start = chunk.last().cloned().unwrap_or(Value::Null);
+            keys.extend(chunk.iter().cloned());
+
+            if done {
+                break;
+            }
+        }
+
+        if let (true, Some(hash)) = (self.limits.prefetch, hash) {
+            self.prefetch(&keys, hash).await?;
+        }
+
+        Ok(json!({ "result": keys }))
+    }
+
+    /// `state_queryStorageAt` with `keys` split into batches of `request_batch_size`.
+    async fn storage_at(&self, params: Value) -> Result<Value, BoxError> {
+        let Some([Value::Array(keys), rest @ ..]) = params.as_array().map(Vec::as_slice) else {
+            return self.call("state_queryStorageAt", params).await;
+        };
+        let hash = rest.first().cloned().unwrap_or(Value::Null);
+
+        if let Some(changes) = self.prefetched_changes(keys, &hash) {
+            return Ok(json!({ "result": [{ "block": hash, "changes": changes }] }));
+        }
+
+        let batch_size = self.limits.request_batch_size.unwrap_or(keys.len()).max(1);
+        let responses = join_all(keys.chunks(batch_size).map(|keys| {
+            let mut params = vec![Value::Array(keys.to_vec())];
+            params.extend(rest.first().cloned());
+            self.call("state_queryStorageAt", Value::Array(params))
+        }))
+        .await;
+
+        let mut block = hash;
+        let mut changes = Vec::new();
+        for response in responses {
+            let response = response?;
+            let Some(sets) = response.get("result").and_then(Value::as_array) else {
+                return Ok(response);
+            };
+
+            for set in sets {
+                block = set.get("block").cloned().unwrap_or(Value::Null);
+                changes.extend(set["changes"].as_array().into_iter().flatten().cloned());
+            }
+        }
+
+        Ok(json!({ "result": [{ "block": block, "changes": changes }] }))
+    }
+
+    /// Fetch values of `keys`, so that later value requests are served from memory.
+    async fn prefetch(&self, keys: &[Value], hash: &Value) -> Result<(), BoxError> {
+        let (Some(hash), false) = (hash.as_str(), keys.is_empty()) else {
+            return Ok(());
+        };
+
+        let response = self.storage_at(json!([keys, hash])).await?;
+        let Some(sets) = response.get("result").and_then(Value::as_array) else {
+            // Values are fetched again on request, which reports the error.
+            return Ok(());
+        };
+
+        let mut prefetched = lock(&self.prefetched);
+        for change in sets
+            .iter()
+            .flat_map(|set| set["changes"].as_array().into_iter().flatten())
+        {
+            if let [Value::String(key), value] =
+                change.as_array().map(Vec::as_slice).unwrap_or_default()
+            {
+                prefetched.insert((key.clone(), hash.to_owned()), value.clone());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Prefetched value of `state_getStorage` request, every value is served once.
+    fn take_prefetched(&self, params: &Value) -> Option<Value> {
+        let [Value::String(key), Value::String(hash)] = params.as_array()?.as_slice() else {
+            return None;
+        };
+
+        lock(&self.prefetched).remove(&(key.clone(), hash.clone()))
+    }
+
+    /// Prefetched values of `state_queryStorageAt` request, if every key was prefetched.
+    fn prefetched_changes(&self, keys: &[Value], hash: &Value) -> Option<Vec<Value>> {
+        let hash = hash.as_str()?;
+        let mut prefetched = lock(&self.prefetched);
+
+        let all = keys.iter().all(|key| {
+            key.as_str()
+                .is_some_and(|key| prefetched.contains_key(&(key.to_owned(), hash.to_owned())))
+        });
+        if !all || prefetched.is_empty() {
+            return None;
+        }
+
+        keys.iter()
+            .map(|key| {
+                let key = key.as_str()?.to_owned();
+                let value = prefetched.remove(&(key.clone(), hash.to_owned()))?;
+                Some(json!([key, value]))
+            })
+            .collect()
+    }
+}
+
+/// Cached response, `chain_getBlockHash` is answered by the finalized block hashes.
+fn cache_lookup(cache: &Cache, method: &str, params: &Value) -> Option<Value> {
+    if method != "chain_getBlockHash" {
+        return cache.response(method, params);
+    }
+
+    let number = match params.as_array()?.as_slice() {
+        [Value::Number(number)] => number.as_u64()?,
+        [Value::String(hex)] => u64::from_str_radix(hex.trim_start_matches("0x"), 16).ok()?,
+        _ => return None,
+    };
+
+    Some(json!({ "result": cache.block_hash(number)? }))
+}
+
+/// Number of the block by its header, which is hex encoded.
+fn header_number(header: &Value) -> Option<u64> {
+    let number = header.get("number")?.as_str()?;
+    u64::from_str_radix(number.trim_start_matches("0x"), 16).ok()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Requests or responses of the single message, which may be a batch.
+pub fn batch(text: &str) -> Vec<Value> {
+    match serde_json::from_str(text) {
+        Ok(Value::Array(items)) => items,
+        Ok(item @ Value::Object(_)) => vec![item],
+        _ => Vec::new(),
+    }
+}
@@ -0,0 +1,422 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::child_trie::{child_id, DEFAULT_CHILD_PREFIX};
+use chainql_core::hex::{to_hex, Hex};
+use hash_db::{HashDBRef, Prefix};
+use parity_scale_codec::{Compact, Decode, DecodeAll};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use serde::Deserialize;
+use sp_core::{storage::StateVersion, Blake2Hasher, H256};
+use sp_trie::{KeySpacedDB, LayoutV0, LayoutV1};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+use trie_db::{DBValue, Trie, TrieDBBuilder, TrieLayout};
+
+/// Storage key/value pairs of a single trie.
+pub type RawStorage = BTreeMap<Hex, Hex>;
+
+/// Main trie and default child tries, keyed by child trie id.
+#[derive(Default)]
+pub struct RawState {
+    pub top: RawStorage,
+    pub children: BTreeMap<Hex, RawStorage>,
+}
+
+#[derive(Deserialize)]
+struct ChainSpec {
+    genesis: Genesis,
+}
+
+#[derive(Deserialize)]
+struct Genesis {
+    raw: Option<RawGenesis>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawGenesis {
+    top: HashMap<String, String>,
+    #[serde(default)]
+    children_default: HashMap<String, HashMap<String, String>>,
+}
+
+/// Read `genesis.raw` section of the chain spec.
+pub fn chain_spec_storage(path: &Path) -> PyResult<RawState> {
+    let spec: ChainSpec = serde_json::from_reader(BufReader::new(File::open(path)?))
+        .map_err(|err| PyValueError::new_err(format!("invalid chain spec: {err}")))?;
+
+    let Some(raw) = spec.genesis.raw else {
+        return Err(PyValueError::new_err(
+            "chain spec has no raw genesis, export it with `--raw` flag",
+        ));
+    };
+
+    let children = raw
+        .children_default
+        .into_iter()
+        .map(|(id, storage)| {
+            let id = from_hex(&id)?;
+            Ok((Hex(child_id(&id).to_vec()), raw_storage(storage)?))
+        })
+        .collect::<PyResult<_>>()?;
+
+    Ok(RawState {
+        top: raw_storage(raw.top)?,
+        children,
+    })
+}
+
+fn raw_storage(storage: HashMap<String, String>) -> PyResult<RawStorage> {
+    storage
+        .into_iter()
+        .map(|(key, value)| Ok((Hex(from_hex(&key)?), Hex(from_hex(&value)?))))
+        .collect()
+}
+
+fn from_hex(hex: &str) -> PyResult<Vec<u8>> {
+    chainql_core::hex::from_hex(hex)
+        .map_err(|err| PyValueError::new_err(format!("invalid hex '{hex}' in chain spec: {err}")))
+}
+
+/// try-runtime snapshot of the version 3, made at the block with `block_hash`.
+#[derive(Decode)]
+struct SnapshotV3 {
+    _version: Compact<u16>,
+    state_version: StateVersion,
+    _block_hash: H256,
+    raw_storage: Vec<(Vec<u8>, (Vec<u8>, i32))>,
+    storage_root: H256,
+}
+
+/// try-runtime snapshot of the version 4, which ends with the block header instead of its hash.
+///
+/// Header is not decoded, as its block number type depends on the chain.
+#[derive(Decode)]
+struct SnapshotV4 {
+    _version: Compact<u16>,
+    state_version: StateVersion,
+    raw_storage: Vec<(Vec<u8>, (Vec<u8>, i32))>,
+    storage_root: H256,
+}
+
+/// Trie database of the snapshot, which is stored by every supported version.
+struct Snapshot {
+    /// Version of the trie layout, with which state was written
+    state_version: StateVersion,
+    /// Trie database nodes with reference counters
+    raw_storage: Vec<(Vec<u8>, (Vec<u8>, i32))>,
+    storage_root: H256,
+}
+
+impl Snapshot {
+    fn decode(bytes: &[u8]) -> PyResult<Self> {
+        let invalid = |err| PyValueError::new_err(format!("invalid snapshot: {err}"));
+
+        let mut input = bytes;
+        let version = Compact::<u16>::decode(&mut input).map_err(invalid)?;
+
+        match version.0 {
+            3 => {
+                let snapshot = SnapshotV3::decode_all(&mut &*bytes).map_err(invalid)?;
+                Ok(Self {
+                    state_version: snapshot.state_version,
+                    raw_storage: snapshot.raw_storage,
+                    storage_root: snapshot.storage_root,
+                })
+            }
+            4 => {
+                let mut input = bytes;
+                let snapshot = SnapshotV4::decode(&mut input).map_err(invalid)?;
+                if input.is_empty() {
+                    return Err(PyValueError::new_err(
+                        "invalid snapshot: block header is missing",
+                    ));
+                }
+
+                Ok(Self {
+                    state_version: snapshot.state_version,
+                    raw_storage: snapshot.raw_storage,
+                    storage_root: snapshot.storage_root,
+                })
+            }
+            version => Err(PyValueError::new_err(format!(
+                "snapshot version {version} is not supported, expected 3 or 4"
+            ))),
+        }
+    }
+}
+
+/// Read main and default child tries of the try-runtime `.snap` file.
+///
+/// Roots of the child tries are not included into the main trie, as children are read separately.
+pub fn snapshot_storage(path: &Path) -> PyResult<RawState> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+
+    let snapshot = Snapshot::decode(&bytes)?;
+
+    let db = NodeDb(
+        snapshot
+            .raw_storage
+            .into_iter()
+            .filter(|(_, (_, rc))| *rc > 0)
+            .map(|(key, (value, _))| (key, value))
+            .collect(),
+    );
+
+    let version = snapshot.state_version;
+    let mut top = read_trie(&db, &snapshot.storage_root, version)?;
+
+    let mut children = BTreeMap::new();
+    let roots = top
+        .range(Hex(DEFAULT_CHILD_PREFIX.to_vec())..)
+        .take_while(|(key, _)| key.0.starts_with(DEFAULT_CHILD_PREFIX));
+    for (key, root) in roots {
+        let id = child_id(&key.0);
+        if root.0.len() != H256::len_bytes() {
+            return Err(PyValueError::new_err(format!(
+                "invalid root of the child trie {}",
+                to_hex(id)
+            )));
+        }
+
+        // Child trie nodes are stored with the child trie id as a key prefix.
+        let db = KeySpacedDB::new(&db, id);
+        let trie = read_trie(&db, &H256::from_slice(&root.0), version)?;
+        children.insert(Hex(id.to_vec()), trie);
+    }
+
+    top.retain(|key, _| !key.0.starts_with(DEFAULT_CHILD_PREFIX));
+
+    Ok(RawState { top, children })
+}
+
+fn read_trie(
+    db: &dyn HashDBRef<Blake2Hasher, DBValue>,
+    root: &H256,
+    version: StateVersion,
+) -> PyResult<RawStorage> {
+    match version {
+        StateVersion::V0 => read_trie_with::<LayoutV0<Blake2Hasher>>(db, root),
+        StateVersion::V1 => read_trie_with::<LayoutV1<Blake2Hasher>>(db, root),
+    }
+}
+
+fn read_trie_with<L: TrieLayout<Hash = Blake2Hasher>>(
+    db: &dyn HashDBRef<Blake2Hasher, DBValue>,
+    root: &H256,
+) -> PyResult<RawStorage> {
+    let trie_error = |err| PyValueError::new_err(format!("invalid snapshot trie: {err}"));
+    let trie = TrieDBBuilder::<L>::new(db, root).build();
+
+    trie.iter()
+        .map_err(trie_error)?
+        .map(|item| {
+            item.map(|(key, value)| (Hex(key), Hex(value)))
+                .map_err(trie_error)
+        })
+        .collect()
+}
+
+/// Trie nodes by their prefixed keys, as stored in the snapshot.
+struct NodeDb(HashMap<Vec<u8>, Vec<u8>>);
+
+impl HashDBRef<Blake2Hasher, DBValue> for NodeDb {
+    fn get(&self, key: &H256, prefix: Prefix) -> Option<DBValue> {
+        let key = memory_db::prefixed_key::<Blake2Hasher>(key, prefix);
+        self.0.get(&key).cloned()
+    }
+
+    fn contains(&self, key: &H256, prefix: Prefix) -> bool {
+        let key = memory_db::prefixed_key::<Blake2Hasher>(key, prefix);
+        self.0.contains_key(&key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parity_scale_codec::Encode;
+    use sp_trie::{KeySpacedDBMut, MemoryDB};
+    use std::path::PathBuf;
+    use trie_db::{TrieDBMutBuilder, TrieMut};
+
+    /// File in the temporary directory, which is removed on drop.
+    struct Fixture(PathBuf);
+
+    impl Fixture {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("chainql-{}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn storage(pairs: &[(&[u8], &[u8])]) -> RawStorage {
+        pairs
+            .iter()
+            .map(|(key, value)| (Hex(key.to_vec()), Hex(value.to_vec())))
+            .collect()
+    }
+
+    fn insert<L: TrieLayout<Hash = Blake2Hasher>>(
+        db: &mut dyn hash_db::HashDB<Blake2Hasher, DBValue>,
+        pairs: &[(&[u8], &[u8])],
+    ) -> H256 {
+        let mut root = H256::default();
+        let mut trie = TrieDBMutBuilder::<L>::new(db, &mut root).build();
+        for (key, value) in pairs {
+            trie.insert(key, value).unwrap();
+        }
+        *trie.root()
+    }
+
+    /// Snapshot in the layout of try-runtime `version`, with a single child trie.
+    fn snapshot<L: TrieLayout<Hash = Blake2Hasher>>(
+        version: u16,
+        state_version: StateVersion,
+        top: &[(&[u8], &[u8])],
+        child: &[(&[u8], &[u8])],
+    ) -> Vec<u8> {
+        let mut db = MemoryDB::<Blake2Hasher>::default();
+
+        let child_root = insert::<L>(&mut KeySpacedDBMut::new(&mut db, b"child"), child);
+        let child_key = [DEFAULT_CHILD_PREFIX, b"child"].concat();
+        let mut top = top.to_vec();
+        top.push((child_key.as_slice(), child_root.as_bytes()));
+        let root = insert::<L>(&mut db, &top);
+
+        let raw_storage: Vec<(Vec<u8>, (Vec<u8>, i32))> = db.drain().into_iter().collect();
+        match version {
+            3 => (
+                Compact(version),
+                state_version,
+                H256::repeat_byte(1),
+                raw_storage,
+                root,
+            )
+                .encode(),
+            // Header with `u32` block number and empty digest.
+            _ => (
+                Compact(version),
+                state_version,
+                raw_storage,
+                root,
+                (
+                    H256::repeat_byte(1),
+                    Compact(1u32),
+                    root,
+                    H256::repeat_byte(2),
+                    Vec::<u8>::new(),
+                ),
+            )
+                .encode(),
+        }
+    }
+
+    #[test]
+    fn chain_spec_raw_genesis() {
+        let spec = Fixture::new(
+            "spec.json",
+            br#"{
+                "name": "Test",
+                "genesis": {
+                    "raw": {
+                        "top": { "0x0102": "0x03", "0x04": "0x" },
+                        "childrenDefault": { "0x6368696c64": { "0x05": "0x06" } }
+                    }
+                }
+            }"#,
+        );
+
+        let state = chain_spec_storage(&spec.0).unwrap();
+        assert_eq!(state.top, storage(&[(&[1, 2], &[3]), (&[4], &[])]));
+        assert_eq!(
+            state.children,
+            BTreeMap::from([(Hex(b"child".to_vec()), storage(&[(&[5], &[6])]))])
+        );
+    }
+
+    #[test]
+    fn chain_spec_without_raw_genesis() {
+        let spec = Fixture::new(
+            "plain-spec.json",
+            br#"{ "genesis": { "runtimeGenesis": {} } }"#,
+        );
+
+        assert!(chain_spec_storage(&spec.0).is_err());
+    }
+
+    #[test]
+    fn snapshot_tries() {
+        let long_value = [7; 64];
+        for (version, state_version) in [3, 4]
+            .into_iter()
+            .flat_map(|version| [(version, StateVersion::V0), (version, StateVersion::V1)])
+        {
+            let top: &[(&[u8], &[u8])] = &[(b"key", b"value"), (b"long", &long_value)];
+            let child: &[(&[u8], &[u8])] = &[(b"nested", b"child value")];
+            let bytes = match state_version {
+                StateVersion::V0 => {
+                    snapshot::<LayoutV0<Blake2Hasher>>(version, state_version, top, child)
+                }
+                StateVersion::V1 => {
+                    snapshot::<LayoutV1<Blake2Hasher>>(version, state_version, top, child)
+                }
+            };
+            let snap = Fixture::new(&format!("v{version}-{state_version:?}.snap"), &bytes);
+
+            let state = snapshot_storage(&snap.0).unwrap();
+            assert_eq!(
+                state.children,
+                BTreeMap::from([(
+                    Hex(b"child".to_vec()),
+                    storage(&[(b"nested", b"child value")])
+                )])
+            );
+            assert_eq!(state.top, storage(top));
+        }
+    }
+
+    #[test]
+    fn snapshot_unknown_versions() {
+        for version in [2, 5] {
+            let bytes = snapshot::<LayoutV1<Blake2Hasher>>(version, StateVersion::V1, &[], &[]);
+            let snap = Fixture::new(&format!("v{version}.snap"), &bytes);
+
+            assert!(snapshot_storage(&snap.0).is_err());
+        }
+    }
+
+    #[test]
+    fn snapshot_without_header() {
+        let mut bytes = snapshot::<LayoutV1<Blake2Hasher>>(4, StateVersion::V1, &[], &[]);
+        // Header is 32 + 1 + 32 + 32 + 1 bytes long.
+        bytes.truncate(bytes.len() - 98);
+        let snap = Fixture::new("headless.snap", &bytes);
+
+        assert!(snapshot_storage(&snap.0).is_err());
+    }
+}
@@ -0,0 +1,671 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    errors::{record_node_failure, record_storage_read, NodeFailure},
+    jsonnet_tokio::current_job,
+    node::{AbortOnDrop, BoxError, Node, Upstream},
+};
+use futures_util::{future::join_all, SinkExt, StreamExt};
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinSet,
+};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        Message,
+    },
+    WebSocketStream,
+};
+
+/// Requests and responses, saved in the record mode by the writer thread.
+type Log = std::sync::mpsc::Sender<Value>;
+
+/// Path of the local endpoint, which follows the [`BlockPin`], relative to the secret one.
+const PINNED_PATH: &str = "/pinned";
+
+/// Way to talk to the node, other than connecting to it directly
+#[derive(Clone)]
+pub enum Transport {
+    /// Save every request and response to the file
+    Record(PathBuf),
+    /// Serve responses from the recording instead of the node
+    Replay(PathBuf),
+}
+
+impl Transport {
+    pub fn new(record: Option<PathBuf>, replay: Option<PathBuf>) -> PyResult<Option<Self>> {
+        match (record, replay) {
+            (Some(_), Some(_)) => Err(PyValueError::new_err(
+                "record and replay can not be used at the same time",
+            )),
+            (Some(path), None) => Ok(Some(Self::Record(path))),
+            (None, Some(path)) => Ok(Some(Self::Replay(path))),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Block, which chainql-core reads instead of the canonical block with the same number.
+///
+/// chainql-core reads state only by block number, so the local endpoint answers
+/// its `chain_getBlockHash` request for the pinned number with the pinned hash.
+/// This way state is read at exactly the requested block, even if it is not
+/// in the canonical chain or the canonical chain was reorganized meanwhile.
+///
+/// Block is pinned for the evaluator job, which reads the state, so requests
+/// made by other jobs are never answered with it.
+#[derive(Clone, Default)]
+pub struct BlockPin(Arc<Mutex<HashMap<u64, (u32, String)>>>);
+
+impl BlockPin {
+    /// Run `f` with `hash` pinned as the block `number` for the current job.
+    pub fn with<T>(&self, number: u32, hash: String, f: impl FnOnce() -> T) -> T {
+        let job = current_job();
+        self.lock().insert(job, (number, hash));
+        let result = f();
+        self.lock().remove(&job);
+        result
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, (u32, String)>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Response to the `chain_getBlockHash` request, if it asks for the number, pinned by the current job.
+    fn reply(&self, request: &Value) -> Option<Value> {
+        if request.get("method")?.as_str()? != "chain_getBlockHash" {
+            return None;
+        }
+
+        let number = match request.get("params")?.as_array()?.as_slice() {
+            [Value::Number(number)] => u32::try_from(number.as_u64()?).ok()?,
+            [Value::String(hex)] => u32::from_str_radix(hex.trim_start_matches("0x"), 16).ok()?,
+            _ => return None,
+        };
+
+        let pin = self.lock();
+        let (pinned, hash) = pin.get(&current_job())?;
+
+        (*pinned == number).then(|| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": request.get("id").cloned().unwrap_or(Value::Null),
+                "result": hash,
+            })
+        })
+    }
+}
+
+/// URLs of the local endpoint, which is used instead of the node.
+pub struct Endpoint {
+    /// URL for the own requests of pychainql
+    pub url: String,
+    /// URL for chainql-core, which requests follow the [`BlockPin`]
+    pub core_url: String,
+    /// Endpoint server, which is stopped with every connection, when the last reference is dropped.
+    ///
+    /// References are held by the chain and its states, so the endpoint lives as long as any of them.
+    pub server: Arc<AbortOnDrop>,
+}
+
+/// Start local endpoint, which forwards requests to the node or serves them by `transport`.
+///
+/// Requests to the node are split by the limits and served from the cache, if any.
+/// Endpoint accepts only connections to the URLs with a random secret path,
+/// so that other local processes can't use the node or the recording through it.
+pub async fn serve(
+    upstream: Upstream,
+    transport: Option<Transport>,
+    pin: BlockPin,
+) -> PyResult<Endpoint> {
+    let mode = match transport {
+        None => Mode::Forward {
+            upstream,
+            log: None,
+        },
+        Some(Transport::Record(path)) => Mode::Forward {
+            upstream,
+            log: Some(spawn_log_writer(File::create(path)?)),
+        },
+        Some(Transport::Replay(path)) => Mode::Replay(Arc::new(Recording::load(&path)?)),
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let path = format!("/{}", secret()?);
+    let url = format!("ws://{}{path}", listener.local_addr()?);
+
+    let server = tokio::spawn(async move {
+        // Connections are aborted together with the server.
+        let mut connections = JoinSet::new();
+
+        while let Ok((stream, _)) = listener.accept().await {
+            let (mode, pin, path) = (mode.clone(), pin.clone(), path.clone());
+            connections.spawn(async move {
+                if let Err(err) = mode.handle(stream, &path, pin).await {
+                    log::warn!("rpc {} connection failed: {err}", mode.name());
+                }
+            });
+
+            while connections.try_join_next().is_some() {}
+        }
+    });
+
+    Ok(Endpoint {
+        core_url: format!("{url}{PINNED_PATH}"),
+        url,
+        server: Arc::new(AbortOnDrop(server)),
+    })
+}
+
+/// Random path segment from the OS generator, which is known only to the endpoint clients.
+fn secret() -> PyResult<String> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes).map_err(|err| {
+        PyRuntimeError::new_err(format!("failed to generate endpoint secret: {err}"))
+    })?;
+
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+#[derive(Clone)]
+enum Mode {
+    /// Forward requests to the node, saving them to the log, if any
+    Forward {
+        upstream: Upstream,
+        log: Option<Log>,
+    },
+    Replay(Arc<Recording>),
+}
+
+impl Mode {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Forward { log: None, .. } => "proxy",
+            Self::Forward { log: Some(_), .. } => "record",
+            Self::Replay(_) => "replay",
+        }
+    }
+
+    async fn handle(&self, stream: TcpStream, path: &str, pin: BlockPin) -> Result<(), BoxError> {
+        let mut pinned = false;
+        let client = accept_hdr_async(
+            stream,
+            |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+                match request.uri().path().strip_prefix(path) {
+                    Some("") => Ok(response),
+                    Some(PINNED_PATH) => {
+                        pinned = true;
+                        Ok(response)
+                    }
+                    _ => {
+                        let mut forbidden = ErrorResponse::new(None);
+                        *forbidden.status_mut() = StatusCode::FORBIDDEN;
+                        Err(forbidden)
+                    }
+                }
+            },
+        )
+        .await?;
+
+        // Only chainql-core connection follows the pin.
+        let pin = pinned.then_some(pin);
+
+        let result = match self {
+            Self::Forward { upstream, log } => {
+                forward(client, upstream.clone(), log.clone(), pin).await
+            }
+            Self::Replay(recording) => replay(client, recording, pin).await,
+        };
+
+        if pinned && result.is_err() {
+            record_node_failure(NodeFailure::Connection);
+        }
+
+        result
+    }
+}
+
+/// Forward requests to the node, saving every response with its request to the `log`.
+///
+/// Requests are split by the limits, responses to the split requests
+/// are merged, so that the client and the log see only the original ones.
+async fn forward(
+    client: WebSocketStream<TcpStream>,
+    upstream: Upstream,
+    log: Option<Log>,
+    pin: Option<BlockPin>,
+) -> Result<(), BoxError> {
+    let (notifications_tx, mut notifications) = mpsc::unbounded_channel();
+    let node = Arc::new(Node::new(upstream, notifications_tx));
+
+    let (client_tx, mut client_rx) = client.split();
+    let client_tx = Arc::new(tokio::sync::Mutex::new(client_tx));
+
+    let requests = async {
+        let mut handlers = JoinSet::new();
+
+        while let Some(message) = client_rx.next().await {
+            let text = match message? {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+
+            let (node, client_tx) = (node.clone(), client_tx.clone());
+            let (log, pin) = (log.clone(), pin.clone());
+
+            // Requests are answered concurrently, as the node would do.
+            handlers.spawn(async move {
+                let reply_to = |request: &Value| handle(&node, request, log.as_ref(), pin.as_ref());
+                let reply = match serde_json::from_str::<Value>(text.as_str())? {
+                    Value::Array(requests) => {
+                        let replies = join_all(requests.iter().map(reply_to)).await;
+                        if pin.is_some() {
+                            observe_batch(&requests);
+                        }
+                        Value::Array(replies)
+                    }
+                    request => reply_to(&request).await,
+                };
+
+                let reply = Message::Text(reply.to_string().into());
+                client_tx.lock().await.send(reply).await?;
+                Ok::<_, BoxError>(())
+            });
+
+            while let Some(result) = handlers.try_join_next() {
+                result??;
+            }
+        }
+
+        Ok::<_, BoxError>(())
+    };
+
+    let notifications = async {
+        while let Some(notification) = notifications.recv().await {
+            // Subscription notifications are replayed after the response to the subscription.
+            if let Some(log) = &log {
+                write_log(log, json!({ "notification": notification }));
+            }
+
+            let notification = Message::Text(notification.to_string().into());
+            client_tx.lock().await.send(notification).await?;
+        }
+        Ok::<_, BoxError>(())
+    };
+
+    tokio::select! {
+        result = requests => result,
+        result = notifications => result,
+    }
+}
+
+/// Response to the single request of the client.
+async fn handle(node: &Node, request: &Value, log: Option<&Log>, pin: Option<&BlockPin>) -> Value {
+    if let Some(reply) = pin.and_then(|pin| pin.reply(request)) {
+        return reply;
+    }
+
+    let method = request
+        .get("method")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+    let mut response = match node.request(method, params.clone()).await {
+        Ok(response) => response,
+        Err(err) => {
+            if pin.is_some() {
+                record_node_failure(NodeFailure::Connection);
+            }
+            json!({ "error": { "code": -32603, "message": format!("node request failed: {err}") } })
+        }
+    };
+
+    if let Some(response) = response.as_object_mut() {
+        response.remove("id");
+        response.remove("jsonrpc");
+    }
+
+    if pin.is_some() {
+        observe(request, &response);
+    }
+
+    if let Some(log) = log {
+        write_log(
+            log,
+            json!({ "method": method, "params": params, "response": response.clone() }),
+        );
+    }
+
+    response["jsonrpc"] = json!("2.0");
+    response["id"] = request.get("id").cloned().unwrap_or(Value::Null);
+    response
+}
+
+fn write_log(log: &Log, entry: Value) {
+    // Writer stops only after every sender is dropped.
+    let _ = log.send(entry);
+}
+
+/// Start the thread, which writes log entries to the `file`, so that the runtime is not blocked.
+///
+/// Every entry is flushed, so the recording is complete up to the last response.
+/// Thread exits, when the endpoint and every its connection are dropped.
+fn spawn_log_writer(file: File) -> Log {
+    let (log, entries) = std::sync::mpsc::channel::<Value>();
+
+    std::thread::spawn(move || {
+        let mut file = BufWriter::new(file);
+        for entry in entries {
+            if let Err(err) = writeln!(file, "{entry}").and_then(|()| file.flush()) {
+                log::warn!("failed to record rpc message: {err}");
+            }
+        }
+    });
+
+    log
+}
+
+/// Answer requests from the recording.
+async fn replay(
+    client: WebSocketStream<TcpStream>,
+    recording: &Recording,
+    pin: Option<BlockPin>,
+) -> Result<(), BoxError> {
+    let (mut tx, mut rx) = client.split();
+
+    let reply = |request: &Value| match pin.as_ref().and_then(|pin| pin.reply(request)) {
+        Some(reply) => reply,
+        None => {
+            let reply = recording.reply(request);
+            if pin.is_some() {
+                observe(request, &reply);
+            }
+            reply
+        }
+    };
+
+    while let Some(message) = rx.next().await {
+        match message? {
+            Message::Text(text) => {
+                let (reply, notifications) = match serde_json::from_str::<Value>(text.as_str())? {
+                    Value::Array(requests) => {
+                        let replies: Vec<_> = requests.iter().map(reply).collect();
+                        if pin.is_some() {
+                            observe_batch(&requests);
+                        }
+                        let notifications = replies
+                            .iter()
+                            .flat_map(|reply| recording.notifications(reply))
+                            .collect();
+                        (Value::Array(replies), notifications)
+                    }
+                    request => {
+                        let reply = reply(&request);
+                        let notifications = recording.notifications(&reply);
+                        (reply, notifications)
+                    }
+                };
+
+                tx.send(Message::Text(reply.to_string().into())).await?;
+                for notification in notifications {
+                    tx.send(Message::Text(notification.to_string().into()))
+                        .await?;
+                }
+            }
+            Message::Ping(data) => tx.send(Message::Pong(data)).await?,
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Remember error of the chainql-core request, so that it is raised as `RpcError`,
+/// and the storage key it read, so that its decoding failure is reported with the key.
+fn observe(request: &Value, response: &Value) {
+    let error = response.get("error");
+    let code = error
+        .and_then(|error| error.get("code"))
+        .and_then(Value::as_i64)
+        .and_then(|code| i32::try_from(code).ok());
+
+    if let Some(code) = code {
+        let message = error
+            .and_then(|error| error.get("message"))
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        record_node_failure(NodeFailure::Rpc(code, message.to_owned()));
+    } else if let Some(key) = storage_read(request) {
+        record_storage_read(key);
+    }
+}
+
+/// Batched storage reads are answered in any order, so none of them is the last one.
+fn observe_batch(requests: &[Value]) {
+    if requests.iter().filter_map(storage_read).count() > 1 {
+        record_storage_read(None);
+    }
+}
+
+/// Key, which value is read by the request, `Some(None)` for reads of several keys.
+fn storage_read(request: &Value) -> Option<Option<String>> {
+    let params = request.get("params")?;
+    let keys = match request.get("method")?.as_str()? {
+        "state_getStorage" | "state_getStorageAt" => {
+            return Some(params.get(0)?.as_str().map(str::to_owned))
+        }
+        "state_queryStorageAt" => params.get(0)?.as_array()?,
+        _ => return None,
+    };
+
+    Some(match keys.as_slice() {
+        [key] => key.as_str().map(str::to_owned),
+        _ => None,
+    })
+}
+
+/// Responses, saved in the record mode.
+///
+/// Same request may be recorded several times with different responses
+/// (e.g. the best block hash), they are replayed in the same order and
+/// the last one is repeated afterwards.
+///
+/// Subscription notifications are sent right after the response
+/// with the subscription id, in the order they were received.
+struct Recording {
+    responses: HashMap<(String, String), Vec<Value>>,
+    cursors: Mutex<HashMap<(String, String), usize>>,
+    notifications: HashMap<String, Vec<Value>>,
+}
+
+impl Recording {
+    fn load(path: &Path) -> PyResult<Self> {
+        let mut responses = HashMap::<_, Vec<_>>::new();
+        let mut notifications = HashMap::<_, Vec<_>>::new();
+
+        for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut entry = serde_json::from_str::<Value>(&line).map_err(|err| {
+                PyValueError::new_err(format!("invalid recording at line {}: {err}", idx + 1))
+            })?;
+
+            if let Some(notification) = entry.get_mut("notification") {
+                let subscription = notification["params"]["subscription"].to_string();
+                notifications
+                    .entry(subscription)
+                    .or_default()
+                    .push(notification.take());
+                continue;
+            }
+
+            let key = Self::key(&entry);
+            responses
+                .entry(key)
+                .or_default()
+                .push(entry["response"].take());
+        }
+
+        Ok(Self {
+            responses,
+            cursors: Mutex::default(),
+            notifications,
+        })
+    }
+
+    /// Notifications of the subscription, which id is the result of the `reply`.
+    fn notifications(&self, reply: &Value) -> Vec<Value> {
+        match reply.get("result") {
+            Some(id @ (Value::String(_) | Value::Number(_))) => self
+                .notifications
+                .get(&id.to_string())
+                .cloned()
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn key(request: &Value) -> (String, String) {
+        let method = request["method"].as_str().unwrap_or_default().to_owned();
+        (method, request["params"].to_string())
+    }
+
+    fn reply(&self, request: &Value) -> Value {
+        let key = Self::key(request);
+
+        let mut reply = match self.responses.get(&key) {
+            Some(responses) => {
+                let mut cursors = self.cursors.lock().unwrap_or_else(PoisonError::into_inner);
+                let cursor = cursors.entry(key).or_default();
+                let response = responses[(*cursor).min(responses.len() - 1)].clone();
+                *cursor += 1;
+                response
+            }
+            None => json!({
+                "error": {
+                    "code": -32601,
+                    "message": format!("no recorded response for {}", key.0),
+                },
+            }),
+        };
+
+        reply["jsonrpc"] = json!("2.0");
+        reply["id"] = request["id"].clone();
+        reply
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{node::Limits, rpc::Rpc};
+
+    fn fixture() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay.jsonl")
+    }
+
+    fn hash(byte: &str) -> String {
+        format!("0x{}", byte.repeat(32))
+    }
+
+    #[test]
+    fn recording_repeats_last_response() {
+        let recording = Recording::load(&fixture()).unwrap();
+        let request = json!({ "jsonrpc": "2.0", "id": 7, "method": "chain_getFinalizedHead" });
+
+        let replies: Vec<_> = (0..3).map(|_| recording.reply(&request)).collect();
+        assert_eq!(replies[0]["result"], json!(hash("00")));
+        assert_eq!(replies[1]["result"], json!(hash("11")));
+        assert_eq!(replies[2]["result"], json!(hash("11")));
+        assert_eq!(replies[0]["id"], json!(7));
+    }
+
+    #[test]
+    fn recording_notifications() {
+        let recording = Recording::load(&fixture()).unwrap();
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "chain_subscribeNewHeads" });
+
+        let reply = recording.reply(&request);
+        let notifications = recording.notifications(&reply);
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["params"]["result"]["number"], json!("0x2"));
+    }
+
+    #[test]
+    fn recording_unknown_request() {
+        let recording = Recording::load(&fixture()).unwrap();
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "system_chain" });
+
+        assert_eq!(recording.reply(&request)["error"]["code"], json!(-32601));
+    }
+
+    #[test]
+    fn pin_answers_only_pinned_number() {
+        let pin = BlockPin::default();
+        let request = |number: u32| json!({ "jsonrpc": "2.0", "id": 1, "method": "chain_getBlockHash", "params": [number] });
+
+        assert!(pin.reply(&request(1)).is_none());
+        let (pinned, other) = pin.with(1, hash("55"), || {
+            (pin.reply(&request(1)), pin.reply(&request(2)))
+        });
+        assert_eq!(pinned.unwrap()["result"], json!(hash("55")));
+        assert!(other.is_none());
+        assert!(pin.reply(&request(1)).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replay_endpoint() {
+        let upstream = Upstream {
+            url: String::new(),
+            limits: Limits::default(),
+            cache: None,
+        };
+        let transport = Some(Transport::Replay(fixture()));
+        let endpoint = serve(upstream, transport, BlockPin::default())
+            .await
+            .unwrap();
+        let rpc = Rpc::new(&endpoint);
+
+        let block = rpc.block_hash(1).await.unwrap();
+        assert_eq!(block, Some(hash("11")));
+
+        let header = rpc.header(&hash("11")).await.unwrap().unwrap();
+        assert_eq!(header.number().unwrap(), 1);
+        assert_eq!(header.parent_hash, hash("00"));
+        assert_eq!(header.state_root, hash("22"));
+    }
+}
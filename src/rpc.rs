@@ -0,0 +1,196 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{node::AbortOnDrop, replay::Endpoint, utils::rpc_error};
+use jsonrpsee::{
+    core::{client::ClientT, params::ArrayParams},
+    rpc_params,
+    ws_client::{WsClient, WsClientBuilder},
+};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{collections::BTreeMap, future::Future, sync::Arc};
+use tokio::sync::OnceCell;
+
+/// Node RPC client for the block lookups, which are not exposed by chainql-core.
+///
+/// Connection is established on the first request. Requests are made through the local
+/// endpoint, which is kept running while any client or chain state uses it.
+#[derive(Clone)]
+pub struct Rpc {
+    url: Arc<str>,
+    client: Arc<OnceCell<WsClient>>,
+    _server: Arc<AbortOnDrop>,
+}
+
+/// Substrate block header, as returned by `chain_getHeader`
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Header {
+    pub parent_hash: String,
+    pub number: String,
+    pub state_root: String,
+}
+
+/// Storage values at the block, as returned by `state_queryStorage`
+#[derive(Deserialize)]
+struct StorageChangeSet {
+    changes: Vec<(String, Option<String>)>,
+}
+
+impl Header {
+    pub fn number(&self) -> PyResult<u32> {
+        let number = self.number.trim_start_matches("0x");
+
+        u32::from_str_radix(number, 16)
+            .map_err(|_| PyValueError::new_err(format!("invalid block number {}", self.number)))
+    }
+}
+
+impl Rpc {
+    pub fn new(endpoint: &Endpoint) -> Self {
+        Self {
+            url: endpoint.url.as_str().into(),
+            client: Arc::default(),
+            _server: endpoint.server.clone(),
+        }
+    }
+
+    async fn request<R: DeserializeOwned>(&self, method: &str, params: ArrayParams) -> PyResult<R> {
+        let client = self
+            .client
+            .get_or_try_init(|| WsClientBuilder::default().build(&*self.url))
+            .await
+            .map_err(rpc_error)?;
+
+        client.request(method, params).await.map_err(rpc_error)
+    }
+
+    pub async fn best_head(&self) -> PyResult<String> {
+        self.request("chain_getBlockHash", rpc_params![]).await
+    }
+
+    pub async fn finalized_head(&self) -> PyResult<String> {
+        self.request("chain_getFinalizedHead", rpc_params![]).await
+    }
+
+    pub async fn block_hash(&self, number: u32) -> PyResult<Option<String>> {
+        self.request("chain_getBlockHash", rpc_params![number])
+            .await
+    }
+
+    pub async fn header(&self, hash: &str) -> PyResult<Option<Header>> {
+        self.request("chain_getHeader", rpc_params![hash]).await
+    }
+
+    pub async fn keys_paged(
+        &self,
+        prefix: &str,
+        count: u32,
+        start_key: Option<&str>,
+        hash: &str,
+    ) -> PyResult<Vec<String>> {
+        self.request(
+            "state_getKeysPaged",
+            rpc_params![prefix, count, start_key, hash],
+        )
+        .await
+    }
+
+    pub async fn child_keys_paged(
+        &self,
+        child: &str,
+        prefix: &str,
+        count: u32,
+        start_key: Option<&str>,
+        hash: &str,
+    ) -> PyResult<Vec<String>> {
+        self.request(
+            "childstate_getKeysPaged",
+            rpc_params![child, prefix, count, start_key, hash],
+        )
+        .await
+    }
+
+    /// Values of `keys` at the block, missing keys are `None`.
+    /// Values of `keys` at the `from` block and at the `to` block, which is not older.
+    ///
+    /// Node reports every value at `from` and only changed values at the later blocks,
+    /// so unchanged values are transferred once.
+    pub async fn storage_range(
+        &self,
+        keys: &[String],
+        from: &str,
+        to: &str,
+    ) -> PyResult<BTreeMap<String, (Option<String>, Option<String>)>> {
+        let sets: Vec<StorageChangeSet> = self
+            .request("state_queryStorage", rpc_params![keys, from, to])
+            .await?;
+
+        let mut values = BTreeMap::new();
+        for (idx, set) in sets.into_iter().enumerate() {
+            for (key, value) in set.changes {
+                if idx == 0 {
+                    values.insert(key, (value.clone(), value));
+                } else {
+                    values.entry(key).or_insert((None, None)).1 = value;
+                }
+            }
+        }
+
+        Ok(values)
+    }
+
+    pub async fn child_storage(
+        &self,
+        child: &str,
+        key: &str,
+        hash: &str,
+    ) -> PyResult<Option<String>> {
+        self.request("childstate_getStorage", rpc_params![child, key, hash])
+            .await
+    }
+
+    pub async fn child_storage_entries(
+        &self,
+        child: &str,
+        keys: &[String],
+        hash: &str,
+    ) -> PyResult<Vec<Option<String>>> {
+        self.request(
+            "childstate_getStorageEntries",
+            rpc_params![child, keys, hash],
+        )
+        .await
+    }
+}
+
+/// Fetch every key, requesting `page_size` keys after the last received one.
+pub async fn paged<F, Fut>(page_size: u32, mut page: F) -> PyResult<Vec<String>>
+where
+    F: FnMut(Option<String>) -> Fut,
+    Fut: Future<Output = PyResult<Vec<String>>>,
+{
+    let mut keys = Vec::new();
+
+    loop {
+        let chunk = page(keys.last().cloned()).await?;
+        let done = chunk.len() < page_size as usize;
+        keys.extend(chunk);
+
+        if done {
+            return Ok(keys);
+        }
+    }
+}
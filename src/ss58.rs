@@ -17,7 +17,7 @@ use pyo3::prelude::*;
 use crate::{ss58_registry::Ss58AccountFormat, value_error};
 use ss58_registry as ss58_crate;
 
-#[pyclass(str)]
+#[pyclass(str, module = "chainql.address")]
 #[derive(Clone, Copy)]
 pub struct Ss58AddressFormat(pub ss58_crate::Ss58AddressFormat);
 
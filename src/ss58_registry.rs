@@ -20,7 +20,7 @@ use crate::{ss58::Ss58AddressFormat, value_error};
 use ss58_registry as ss58;
 
 /// A known address (sub)format/network ID for SS58
-#[pyclass(frozen, eq, eq_int, str, hash)]
+#[pyclass(frozen, eq, eq_int, str, hash, module = "chainql.address")]
 #[derive(Copy, Clone, Hash, PartialEq, Eq, Debug)]
 #[repr(u16)]
 #[allow(clippy::enum_variant_names)]
@@ -12,9 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use pyo3::exceptions::PyKeyboardInterrupt;
+use crate::{
+    errors::{
+        cancelled_error, connection_error, evaluation_error, rpc_call_error, take_node_failure,
+        take_python_error, with_trace, NodeFailure,
+    },
+    jsonnet_tokio::is_cancelled,
+};
+use jrsonnet_evaluator::error::ErrorKind;
 use pyo3::{
-    exceptions::{PyRuntimeError, PyTypeError, PyValueError},
+    exceptions::{PyTypeError, PyValueError},
     prelude::*,
 };
 
@@ -27,16 +34,78 @@ pub fn type_error(py: Python<'_>, description: impl ToString, cause: PyErr) -> P
 
 #[inline]
 pub fn jsonnet_error(err: jrsonnet_evaluator::error::Error) -> PyErr {
-    let human_err = err.error().to_string();
+    jsonnet_error_with(err, evaluation_error)
+}
+
+/// Same as [`jsonnet_error`], with `fallback` for errors, which are not caused by the node.
+pub fn jsonnet_error_with(
+    err: jrsonnet_evaluator::error::Error,
+    fallback: impl FnOnce(String) -> PyErr,
+) -> PyErr {
+    let trace = err
+        .trace()
+        .0
+        .iter()
+        .map(|frame| match &frame.location {
+            Some(location) => {
+                let source = &location.0;
+                let start = &source.map_source_locations(&[location.1])[0];
+                format!(
+                    "{} at {}:{}:{}",
+                    frame.desc,
+                    source.source_path(),
+                    start.line,
+                    start.column
+                )
+            }
+            None => frame.desc.clone(),
+        })
+        .collect();
+
+    with_trace(chainql_error(&err, fallback), trace)
+}
 
-    if human_err.ends_with("cancelled") {
-        return PyKeyboardInterrupt::new_err(());
+/// Convert jsonnet error to the matching `chainql.errors` exception.
+///
+/// Runtime errors, raised after the local endpoint saw a failed chainql-core
+/// request of the current job, are attributed to that failure. `fallback` is used for the rest,
+/// exception of the failed python callable is chained as its `__cause__`.
+#[inline]
+pub fn chainql_error(
+    err: &jrsonnet_evaluator::error::Error,
+    fallback: impl FnOnce(String) -> PyErr,
+) -> PyErr {
+    if is_cancelled() {
+        return cancelled_error();
     }
 
-    if let Some(human_err) = human_err.strip_prefix("runtime error: ") {
-        PyRuntimeError::new_err(human_err.to_owned())
-    } else {
-        PyRuntimeError::new_err(human_err)
+    let ErrorKind::RuntimeError(message) = err.error() else {
+        return fallback(err.error().to_string());
+    };
+
+    if let Some(cause) = take_python_error() {
+        let err = fallback(message.to_string());
+        Python::with_gil(|py| err.set_cause(py, Some(cause)));
+        return err;
+    }
+
+    match take_node_failure(message) {
+        Some(NodeFailure::Rpc(code, _)) => rpc_call_error(Some(code), message.to_string()),
+        Some(NodeFailure::Connection) => connection_error(message.to_string()),
+        None => fallback(message.to_string()),
+    }
+}
+
+#[inline]
+pub fn rpc_error(err: jsonrpsee::core::ClientError) -> PyErr {
+    use jsonrpsee::core::ClientError;
+
+    match err {
+        ClientError::Call(err) => rpc_call_error(Some(err.code()), err.message().to_owned()),
+        ClientError::Transport(_) | ClientError::RestartNeeded(_) | ClientError::RequestTimeout => {
+            connection_error(err.to_string())
+        }
+        err => rpc_call_error(None, err.to_string()),
     }
 }
 
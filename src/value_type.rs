@@ -0,0 +1,434 @@
+// Copyright 2024 Valery Klachkov
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    errors::{last_storage_read, storage_decode_error, ValuePath},
+    jsonnet_tokio::Local,
+    utils::{jsonnet_error, jsonnet_error_with},
+};
+use chainql_core::hex::{to_hex, Hex};
+use either::Either;
+use jrsonnet_evaluator::{val::ArrValue, ObjValue, Val};
+use pyo3::PyErr;
+use std::sync::Arc;
+
+/// Where the value was taken from.
+///
+/// Path is reported in errors, storage object is used to find the value type.
+#[derive(Clone, Default)]
+pub struct ValueOrigin {
+    pub path: ValuePath,
+    /// Storage object with `_meta`, which the path starts from
+    storage: Option<Arc<Local<ObjValue>>>,
+    /// Whether byte arrays are returned as `chainql.hex.HexBytes`
+    pub hex_bytes: bool,
+}
+
+impl ValueOrigin {
+    /// Origin of the storage object itself.
+    pub fn storage(storage: Arc<Local<ObjValue>>) -> Self {
+        Self {
+            storage: Some(storage),
+            ..Self::default()
+        }
+    }
+
+    /// Origin of the nested field or element.
+    pub fn join(&self, segment: Either<String, usize>) -> Self {
+        Self {
+            path: self.path.join(segment),
+            ..self.clone()
+        }
+    }
+
+    pub fn with_hex_bytes(&self, hex_bytes: bool) -> Self {
+        Self {
+            hex_bytes,
+            ..self.clone()
+        }
+    }
+
+    /// Type of the value, if it was read from the storage and metadata describes it.
+    pub fn value_type(&self) -> Option<ValueType> {
+        let types = StorageTypes::of(self.storage.as_ref()?)?;
+
+        self.path
+            .segments()
+            .iter()
+            .try_fold(ValueType::root(types), |ty, segment| ty.child(segment))
+    }
+
+    /// Whether the value is a byte array, which should be returned as `HexBytes`.
+    pub fn is_hex_bytes(&self) -> bool {
+        self.hex_bytes && self.value_type().is_some_and(|ty| ty.is_bytes())
+    }
+
+    /// Convert error of the value evaluation, reporting it at the path.
+    ///
+    /// Storage items fail to evaluate when their values can not be decoded, such failures
+    /// are raised as `StorageDecodeError` with the key, if it is known.
+    pub fn error(&self, err: jrsonnet_evaluator::error::Error) -> PyErr {
+        let err = match self.storage_entry() {
+            Some(entry) => jsonnet_error_with(err, |message| {
+                storage_decode_error(entry.failed_key(), message)
+            }),
+            None => jsonnet_error(err),
+        };
+        self.path.attach(err)
+    }
+
+    /// Storage item, which the value belongs to.
+    fn storage_entry(&self) -> Option<StorageEntry> {
+        let storage = self.storage.as_ref()?;
+        let [Either::Left(pallet), Either::Left(item), ..] = self.path.segments() else {
+            return None;
+        };
+
+        storage_entries(storage)?
+            .into_iter()
+            .find(|entry| &entry.pallet == pallet && &entry.item == item)
+    }
+}
+
+/// Storage item, as described by the runtime metadata.
+pub struct StorageEntry {
+    pub pallet: String,
+    /// Storage prefix of the pallet, which may differ from its name
+    pub prefix: String,
+    pub item: String,
+    /// Number of the storage map keys, zero for plain values
+    pub keys: usize,
+}
+
+impl StorageEntry {
+    /// Raw key prefix of the pallet storage.
+    pub fn pallet_prefix(&self) -> String {
+        twox128_hex(&self.prefix)
+    }
+
+    /// Raw key prefix of the item, which is the whole key of plain values.
+    pub fn key_prefix(&self) -> String {
+        format!("{}{}", self.pallet_prefix(), &twox128_hex(&self.item)[2..])
+    }
+
+    /// Key of the item, which value failed to decode.
+    ///
+    /// Plain values have a single key, map values are decoded right after chainql-core
+    /// reads them, so the last key, read by the current job, is the failed one.
+    fn failed_key(&self) -> Option<String> {
+        let prefix = self.key_prefix();
+        if self.keys == 0 {
+            return Some(prefix);
+        }
+
+        last_storage_read().filter(|key| key.starts_with(&prefix))
+    }
+}
+
+fn twox128_hex(name: &str) -> String {
+    to_hex(&chainql_core::builtin_twox128(Hex(name.as_bytes().to_vec())).0)
+}
+
+/// Storage items of every pallet, described by `_meta` of the storage object.
+pub fn storage_entries(storage: &ObjValue) -> Option<Vec<StorageEntry>> {
+    let types = StorageTypes::of(storage)?;
+
+    let mut entries = Vec::new();
+    for pallet in types
+        .pallets
+        .iter()
+        .filter_map(|pallet| pallet.ok().and_then(as_obj))
+    {
+        let name = field(&pallet, "name").and_then(as_string)?;
+        // Pallets without storage have `null` instead of it.
+        let Some(storage) = field(&pallet, "storage").and_then(as_obj) else {
+            continue;
+        };
+        let prefix = field(&storage, "prefix").and_then(as_string)?;
+
+        for entry in as_arr(field(&storage, "entries")?)?.iter() {
+            let entry = as_obj(entry.ok()?)?;
+            let ty = as_obj(field(&entry, "ty")?)?;
+            let keys = match field(&ty, "Map") {
+                Some(map) => as_arr(field(&as_obj(map)?, "hashers")?)?.len(),
+                None => 0,
+            };
+
+            entries.push(StorageEntry {
+                pallet: name.clone(),
+                prefix: prefix.clone(),
+                item: field(&entry, "name").and_then(as_string)?,
+                keys,
+            });
+        }
+    }
+
+    Some(entries)
+}
+
+/// Pallets and types of the runtime metadata.
+#[derive(Clone)]
+struct StorageTypes {
+    pallets: ArrValue,
+    types: ArrValue,
+}
+
+impl StorageTypes {
+    /// Metadata of the storage object, exposed by chainql-core as `_meta`.
+    fn of(storage: &ObjValue) -> Option<Self> {
+        let meta = as_obj(field(storage, "_meta")?)?;
+
+        // Metadata may be wrapped into the version, e.g. `{"V14": {...}}`.
+        let meta = match field(&meta, "pallets") {
+            Some(_) => meta,
+            None => ["V15", "V14"]
+                .into_iter()
+                .find_map(|version| field(&meta, version).and_then(as_obj))?,
+        };
+
+        Some(Self {
+            pallets: as_arr(field(&meta, "pallets")?)?,
+            types: as_arr(field(&as_obj(field(&meta, "types")?)?, "types")?)?,
+        })
+    }
+
+    /// Definition of the type, e.g. `("sequence", {"type": 2})`.
+    fn def(&self, id: usize) -> Option<(String, Val)> {
+        let by_index = self.types.get(id).ok().flatten().and_then(as_obj);
+        let entry = match by_index {
+            Some(entry) if field(&entry, "id").and_then(as_index) == Some(id) => entry,
+            _ => self
+                .types
+                .iter()
+                .filter_map(|entry| entry.ok().and_then(as_obj))
+                .find(|entry| field(entry, "id").and_then(as_index) == Some(id))?,
+        };
+
+        let def = as_obj(field(&as_obj(field(&entry, "type")?)?, "def")?)?;
+        let kind = def.fields_ex(false, false).into_iter().next()?;
+        let value = field(&def, kind.as_str())?;
+
+        Some((kind.as_str().to_owned(), value))
+    }
+
+    fn pallet_storage(&self, pallet: &str) -> Option<ArrValue> {
+        let pallet = find_named(&self.pallets, pallet)?;
+        as_arr(field(&as_obj(field(&pallet, "storage")?)?, "entries")?)
+    }
+}
+
+/// Position of the value in the storage.
+#[derive(Clone)]
+enum Position {
+    /// Storage object, which fields are pallets
+    Root,
+    /// Pallet, which fields are storage entries
+    Pallet(ArrValue),
+    /// Storage map with the number of keys left and the value type
+    Keys(usize, usize),
+    /// Value of the type
+    Type(usize),
+    /// Named or unnamed fields of the struct or the enum variant
+    Fields(ArrValue),
+}
+
+/// Type of the storage value, resolved from the runtime metadata.
+///
+/// chainql-core decodes byte arrays to hex strings, so their type is the only
+/// way to tell them from the text values.
+#[derive(Clone)]
+pub struct ValueType {
+    types: StorageTypes,
+    position: Position,
+}
+
+impl ValueType {
+    fn root(types: StorageTypes) -> Self {
+        Self {
+            types,
+            position: Position::Root,
+        }
+    }
+
+    fn at(&self, position: Position) -> Self {
+        Self {
+            types: self.types.clone(),
+            position,
+        }
+    }
+
+    /// Type of the field or element.
+    pub fn child(&self, segment: &Either<String, usize>) -> Option<Self> {
+        let position = match (&self.position, segment) {
+            (Position::Root, Either::Left(pallet)) => {
+                Position::Pallet(self.types.pallet_storage(pallet)?)
+            }
+            (Position::Pallet(entries), Either::Left(name)) => {
+                let entry = find_named(entries, name)?;
+                let ty = as_obj(field(&entry, "ty")?)?;
+
+                match field(&ty, "Plain") {
+                    Some(plain) => Position::Type(as_index(plain)?),
+                    None => {
+                        let map = as_obj(field(&ty, "Map")?)?;
+                        let keys = as_arr(field(&map, "hashers")?)?.len();
+                        let value = as_index(field(&map, "value")?)?;
+                        self.keys(keys, value)
+                    }
+                }
+            }
+            // Maps with several keys are nested by every key.
+            (&Position::Keys(keys, value), _) => self.keys(keys - 1, value),
+            (&Position::Type(id), segment) => return self.type_child(id, segment),
+            (Position::Fields(fields), Either::Left(name)) => {
+                Position::Type(as_index(field(&find_named(fields, name)?, "type")?)?)
+            }
+            (Position::Fields(fields), &Either::Right(idx)) => {
+                let field_ty = field(&as_obj(fields.get(idx).ok()??)?, "type")?;
+                Position::Type(as_index(field_ty)?)
+            }
+            _ => return None,
+        };
+
+        Some(self.at(position))
+    }
+
+    fn keys(&self, keys: usize, value: usize) -> Position {
+        match keys {
+            0 => Position::Type(value),
+            keys => Position::Keys(keys, value),
+        }
+    }
+
+    fn type_child(&self, id: usize, segment: &Either<String, usize>) -> Option<Self> {
+        let (kind, def) = self.types.def(id)?;
+
+        match (kind.as_str(), segment) {
+            ("composite", _) => self.fields(as_obj(def)?)?.child(segment),
+            ("variant", Either::Left(name)) => {
+                let variants = as_arr(field(&as_obj(def)?, "variants")?)?;
+                match find_named(&variants, name) {
+                    Some(variant) => Some(self.fields(variant)?),
+                    // Option is decoded as the inner value.
+                    None => self.some(&variants)?.child(segment),
+                }
+            }
+            ("variant", _) => {
+                let variants = as_arr(field(&as_obj(def)?, "variants")?)?;
+                self.some(&variants)?.child(segment)
+            }
+            ("sequence" | "array", Either::Right(_)) => {
+                Some(self.at(Position::Type(as_index(field(&as_obj(def)?, "type")?)?)))
+            }
+            ("tuple", &Either::Right(idx)) => {
+                Some(self.at(Position::Type(as_index(as_arr(def)?.get(idx).ok()??)?)))
+            }
+            ("compact", _) => self
+                .at(Position::Type(as_index(field(&as_obj(def)?, "type")?)?))
+                .child(segment),
+            _ => None,
+        }
+    }
+
+    /// Fields of the struct or the variant, single unnamed field is decoded as its value.
+    fn fields(&self, owner: ObjValue) -> Option<Self> {
+        let fields = field(&owner, "fields")
+            .and_then(as_arr)
+            .unwrap_or_else(ArrValue::empty);
+
+        if fields.len() == 1 {
+            let single = as_obj(fields.get(0).ok()??)?;
+            if field(&single, "name").is_none_or(|name| matches!(name, Val::Null)) {
+                return Some(self.at(Position::Type(as_index(field(&single, "type")?)?)));
+            }
+        }
+
+        Some(self.at(Position::Fields(fields)))
+    }
+
+    /// Type of `Some` value, if the enum is an `Option`.
+    fn some(&self, variants: &ArrValue) -> Option<Self> {
+        find_named(variants, "None")?;
+        let some = self.fields(find_named(variants, "Some")?)?;
+        matches!(some.position, Position::Type(_)).then_some(some)
+    }
+
+    /// Whether chainql-core decodes the value as a hex string of bytes.
+    pub fn is_bytes(&self) -> bool {
+        let Position::Type(id) = self.position else {
+            return false;
+        };
+        let Some((kind, def)) = self.types.def(id) else {
+            return false;
+        };
+
+        match kind.as_str() {
+            "sequence" | "array" => as_obj(def)
+                .and_then(|def| as_index(field(&def, "type")?))
+                .and_then(|id| self.types.def(id))
+                .is_some_and(|(kind, def)| {
+                    kind == "primitive" && as_string(def).as_deref() == Some("u8")
+                }),
+            "composite" => as_obj(def)
+                .and_then(|def| self.fields(def))
+                .is_some_and(|ty| ty.is_bytes()),
+            "variant" => as_obj(def)
+                .and_then(|def| as_arr(field(&def, "variants")?))
+                .and_then(|variants| self.some(&variants))
+                .is_some_and(|ty| ty.is_bytes()),
+            _ => false,
+        }
+    }
+}
+
+fn field(obj: &ObjValue, key: &str) -> Option<Val> {
+    obj.get(key.into()).ok().flatten()
+}
+
+fn as_obj(value: Val) -> Option<ObjValue> {
+    match value {
+        Val::Obj(obj) => Some(obj),
+        _ => None,
+    }
+}
+
+fn as_arr(value: Val) -> Option<ArrValue> {
+    match value {
+        Val::Arr(arr) => Some(arr),
+        _ => None,
+    }
+}
+
+fn as_string(value: Val) -> Option<String> {
+    match value {
+        Val::Str(s) => Some(s.into_flat().as_str().to_owned()),
+        _ => None,
+    }
+}
+
+fn as_index(value: Val) -> Option<usize> {
+    match value {
+        // Same as for the block timestamp, fractional and negative values are rejected by parsing.
+        Val::Num(num) => num.get().to_string().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Element of the metadata array with the given `name`.
+fn find_named(items: &ArrValue, name: &str) -> Option<ObjValue> {
+    items
+        .iter()
+        .filter_map(|item| item.ok().and_then(as_obj))
+        .find(|item| field(item, "name").and_then(as_string).as_deref() == Some(name))
+}
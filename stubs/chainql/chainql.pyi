@@ -12,22 +12,244 @@
 # See the License for the specific language governing permissions and
 # limitations under the License.
 
-from typing import Callable, Mapping, Optional, Sequence
+from inspect import Signature
+from os import PathLike
+from typing import (
+    IO,
+    Any,
+    AsyncIterator,
+    Awaitable,
+    Callable,
+    ClassVar,
+    Iterable,
+    Iterator,
+    Literal,
+    Mapping,
+    Optional,
+    Sequence,
+    TypedDict,
+    overload,
+)
+
+import pyarrow
+
+from .hex import HexBytes
+
+class StateDiff(TypedDict):
+    """Changed values by their paths, added and removed values are present only at one side"""
+
+    added: dict[tuple[str, ...], Any]
+    removed: dict[tuple[str, ...], Any]
+    changed: dict[tuple[str, ...], tuple[Any, Any]]
 
 class JsonnetObject(Mapping):
+    __hash__: ClassVar[None]  # type: ignore[assignment]
+    """Objects are lazy and may hold the whole chain state, so they are not hashable"""
+
     def keys(self, include_hidden: bool = False): ...
     def values(self, include_hidden: bool = False): ...
     def items(self, include_hidden: bool = False): ...
 
+    def aget(self, key: str) -> Awaitable[Any]:
+        """Get field value without blocking the event loop"""
+        ...
+
+    def aitems(self, include_hidden: bool = False) -> AsyncIterator[tuple[str, Any]]:
+        """Asynchronously iterate over `(key, value)` pairs"""
+        ...
+
+    def __aiter__(self) -> AsyncIterator[str]: ...
+
     def manifest_json(self, minified: bool = True) -> str:
         ...
 
+    def manifest(
+        self,
+        format: Literal["json", "yaml", "toml", "ini"] = "json",
+        indent: Optional[int] = None,
+        sort_keys: bool = False,
+    ) -> str:
+        """
+        Serialize to `format`, zero `indent` produces minified JSON.
+
+        By default JSON is minified, same as `manifest_json`, other formats are indented by 2 spaces.
+        """
+        ...
+
+    def manifest_yaml(self, indent: int = 2, sort_keys: bool = False) -> str: ...
+    def manifest_toml(self, indent: int = 2, sort_keys: bool = False) -> str: ...
+    def manifest_ini(self, sort_keys: bool = False) -> str: ...
+
+    def manifest_json_to(
+        self,
+        target: str | PathLike[str] | IO[str] | IO[bytes],
+        minified: bool = True,
+        ndjson: bool = False,
+        sort_keys: bool = False,
+    ) -> None:
+        """
+        Write JSON to the path or file object, evaluating one field at a time.
+
+        With `ndjson`, every field is written on its own line.
+        """
+        ...
+
+    def to_arrow(self) -> pyarrow.RecordBatch:
+        """
+        Flatten storage map into record batch.
+
+        Storage keys go to the `key` column, struct values are split into
+        columns by fields and nested structs into `outer.inner` columns,
+        other values go to the `value` column. Fields named `key` or `value`
+        are prefixed with `value.`. u128 balances are kept exact as
+        `decimal128(38, 0)` or string columns.
+        """
+        ...
+
+    def to_parquet(self, path: str | PathLike[str]) -> None:
+        """Write storage map to the parquet file, same columns as `to_arrow`"""
+        ...
+
+    def diff(self, other: JsonnetObject) -> StateDiff:
+        """
+        Difference with `other`, e.g. two dumps of the same chain.
+
+        Nested objects are compared field by field, other values as a whole.
+        """
+        ...
+
+    @staticmethod
+    def from_python(value: dict[str, Any]) -> JsonnetObject:
+        """Create object from python dictionary"""
+        ...
+
+    @overload
+    def to_python(
+        self, depth: Literal[0], include_hidden: bool = False, hex_bytes: Optional[bool] = None
+    ) -> JsonnetObject: ...
+    @overload
+    def to_python(
+        self, depth: Optional[int] = None, include_hidden: bool = False, hex_bytes: Optional[bool] = None
+    ) -> dict[str, Any]:
+        """
+        Convert to plain python data, containers below `depth` stay lazy,
+        so with `depth=0` the value itself is returned.
+
+        With `hex_bytes`, byte arrays are returned as `chainql.hex.HexBytes`,
+        by default it is set by `with_hex_bytes`.
+        """
+        ...
+
+    def with_hex_bytes(self, enabled: bool = True) -> JsonnetObject:
+        """
+        Same object, which returns byte arrays as `chainql.hex.HexBytes`.
+
+        Byte arrays are found by the runtime metadata, so only the values read
+        from the block state or the storage dump are converted.
+        """
+        ...
+
 class JsonnetArray(Sequence):
+    __hash__: ClassVar[None]  # type: ignore[assignment]
+    """Arrays are compared by value, which would be evaluated on every hash, so they are not hashable"""
+
+    @overload
+    def __getitem__(self, index: int) -> Any: ...
+    @overload
+    def __getitem__(self, index: slice) -> JsonnetArray:
+        """Lazy sub-array, elements are not evaluated"""
+        ...
+
+    def __reversed__(self) -> Iterator[Any]: ...
+
+    def index(self, value: Any, start: int = 0, stop: Optional[int] = None) -> int:
+        """Index of the first element equal to `value`"""
+        ...
+
+    def count(self, value: Any) -> int:
+        """Number of elements equal to `value`"""
+        ...
+
+    def aget(self, index: int) -> Awaitable[Any]:
+        """Get element without blocking the event loop"""
+        ...
+
+    def __aiter__(self) -> AsyncIterator[Any]: ...
+
     def manifest_json(self, minified: bool = True) -> str:
         ...
 
+    def manifest(
+        self,
+        format: Literal["json", "yaml", "toml", "ini"] = "json",
+        indent: Optional[int] = None,
+        sort_keys: bool = False,
+    ) -> str:
+        """
+        Serialize to `format`, zero `indent` produces minified JSON.
+
+        By default JSON is minified, same as `manifest_json`, other formats are indented by 2 spaces.
+        """
+        ...
+
+    def manifest_yaml(self, indent: int = 2, sort_keys: bool = False) -> str: ...
+    def manifest_toml(self, indent: int = 2, sort_keys: bool = False) -> str: ...
+    def manifest_ini(self, sort_keys: bool = False) -> str: ...
+
+    def manifest_json_to(
+        self,
+        target: str | PathLike[str] | IO[str] | IO[bytes],
+        minified: bool = True,
+        ndjson: bool = False,
+        sort_keys: bool = False,
+    ) -> None:
+        """
+        Write JSON to the path or file object, evaluating one field at a time.
+
+        With `ndjson`, every field is written on its own line.
+        """
+        ...
+
+    @staticmethod
+    def from_python(value: list[Any]) -> JsonnetArray:
+        """Create array from python list"""
+        ...
+
+    @overload
+    def to_python(
+        self, depth: Literal[0], include_hidden: bool = False, hex_bytes: Optional[bool] = None
+    ) -> JsonnetArray: ...
+    @overload
+    def to_python(
+        self, depth: Optional[int] = None, include_hidden: bool = False, hex_bytes: Optional[bool] = None
+    ) -> list[Any]:
+        """
+        Convert to plain python data, containers below `depth` stay lazy,
+        so with `depth=0` the value itself is returned.
+
+        With `hex_bytes`, byte arrays are returned as `chainql.hex.HexBytes`,
+        by default it is set by `with_hex_bytes`.
+        """
+        ...
+
+    def with_hex_bytes(self, enabled: bool = True) -> JsonnetArray:
+        """
+        Same array, which returns byte arrays as `chainql.hex.HexBytes`.
+
+        Byte arrays are found by the runtime metadata, so only the values read
+        from the block state or the storage dump are converted.
+        """
+        ...
+
 class JsonnetFunc(Callable):
-    ...
+    def __call__(self, *args: Any, **kwargs: Any) -> Any:
+        """Call jsonnet function, keyword arguments are passed as named arguments"""
+        ...
+
+    @property
+    def __signature__(self) -> Signature:
+        """Parameters of the jsonnet function, default values are shown as `...`"""
+        ...
 
 class ChainOpts:
     """Selection of optional flags for chain data processing"""
@@ -38,18 +260,283 @@ class ChainOpts:
     include_defaults: bool
     """Should default values be included in output"""
 
-    def __init__(self, omit_empty: bool = True, include_defaults: bool = True) -> None:
+    key_page_size: Optional[int]
+    """
+    Maximum number of keys, requested from the node by a single `state_getKeysPaged` call
+
+    Larger requests of chainql-core are split into pages, smaller ones are sent as is.
+    """
+
+    max_parallel_requests: Optional[int]
+    """Maximum number of RPC requests to the node in flight"""
+
+    request_batch_size: Optional[int]
+    """Maximum number of storage keys, requested from the node by a single `state_queryStorageAt` call"""
+
+    preload: bool
+    """
+    Whether values of the storage maps are fetched together with their keys
+
+    Otherwise values are fetched when they are read, unless chainql-core preloads them.
+    """
+
+    cache_dir: Optional[str | PathLike[str]]
+    """
+    Directory for runtime metadata and finalized storage, fetched from the node
+
+    Every chain is cached in the subdirectory, named by its genesis hash.
+    """
+
+    offline: bool
+    """
+    Whether queries are only served from `cache_dir`, without connecting to the node
+
+    Latest and finalized blocks are the last ones, seen while the node was used.
+    """
+
+    def __init__(
+        self,
+        omit_empty: bool = True,
+        include_defaults: bool = True,
+        key_page_size: Optional[int] = None,
+        max_parallel_requests: Optional[int] = None,
+        request_batch_size: Optional[int] = None,
+        preload: bool = False,
+        cache_dir: Optional[str | PathLike[str]] = None,
+        offline: bool = False,
+    ) -> None:
+        """
+        Options left as `None` use chainql defaults
+
+        Zero limits, `key_page_size` above 1000 and `offline` without `cache_dir` raise `ValueError`.
+        """
+        ...
+
+class BlockRef:
+    """Reference to the block, which state is being read"""
+
+    number: int
+    """Block number"""
+
+    hash: str
+    """Block hash"""
+
+    parent_hash: str
+    """Hash of the parent block"""
+
+    state_root: str
+    """Storage trie root after the block execution"""
+
+    timestamp: Optional[int]
+    """Block timestamp in milliseconds, if chain has `Timestamp` pallet"""
+
+    def __init__(
+        self,
+        number: int,
+        hash: str,
+        parent_hash: str,
+        state_root: str,
+        timestamp: Optional[int] = None,
+    ) -> None: ...
+
+class BlockState(JsonnetObject):
+    """Chain state at the specific block, compared and hashed by identity"""
+
+    def __eq__(self, other: object) -> bool: ...
+    def __hash__(self) -> int: ...  # type: ignore[override]
+
+    @property
+    def block(self) -> BlockRef:
+        """Block, which state is being read, `timestamp` is read from the state on first access"""
+        ...
+
+    @property
+    def _child(self) -> ChildTries:
+        """Default child tries at the block, `state._child[child_key]`"""
         ...
 
+class DumpState(JsonnetObject):
+    """State, decoded from the raw storage by `dump`"""
+
+    @property
+    def _child(self) -> ChildTries:
+        """Default child tries of the dump, `state._child[child_key]`"""
+        ...
+
+class ChildTries:
+    """Default child tries of the block state or the dump"""
+
+    def __getitem__(self, key: bytes | str) -> ChildTrie:
+        """Child trie with the given id, with or without `:child_storage:default:` prefix"""
+        ...
+
+    def keys(self) -> list[HexBytes]:
+        """Ids of every default child trie in the state"""
+        ...
+
+    def __iter__(self) -> Iterator[HexBytes]: ...
+
+class ChildTrie:
+    """
+    Default child trie of the block state
+
+    Runtime metadata doesn't describe child trie contents,
+    so keys and values are returned as raw bytes.
+    """
+
+    id: HexBytes
+    """Child trie id without the `:child_storage:default:` prefix"""
+
+    def __getitem__(self, key: bytes | str) -> HexBytes: ...
+    def get(self, key: bytes | str, default: Any = None) -> HexBytes | Any: ...
+    def __contains__(self, key: bytes | str) -> bool: ...
+
+    def keys(self, prefix: Optional[bytes | str] = None) -> list[HexBytes]:
+        """Keys of the child trie, starting with `prefix`"""
+        ...
+
+    def items(self, prefix: Optional[bytes | str] = None) -> list[tuple[HexBytes, HexBytes]]:
+        """`(key, value)` pairs of the child trie, which keys start with `prefix`"""
+        ...
+
+    def __iter__(self) -> Iterator[HexBytes]: ...
+
 class Chain:
-    def __init__(url: str, opts: Optional[ChainOpts] = None) -> None:
+    def __init__(
+        url: Optional[str] = None,
+        opts: Optional[ChainOpts] = None,
+        record: Optional[str | PathLike[str]] = None,
+        replay: Optional[str | PathLike[str]] = None,
+    ) -> None:
+        """
+        Connect to the node at `url`.
+
+        With `record`, every RPC request and response, including subscription
+        notifications, is saved to the file.
+        With `replay`, responses are served from such file and node is not used,
+        so `url` may be omitted.
+        """
         ...
     
-    def latest(self) -> JsonnetObject:
+    def latest(self) -> BlockState:
+        ...
+
+    def finalized(self) -> BlockState:
+        """State at the last finalized block"""
+        ...
+
+    def block(self, block: int) -> BlockState:
+        ...
+
+    def at_hash(self, hash: bytes | str) -> BlockState:
+        """State at the block with the given hash, which may be outside of the canonical chain"""
+        ...
+
+    def diff(self, from_block: int, to_block: int, pallets: Optional[Sequence[str]] = None) -> StateDiff:
+        """
+        Storage changes between two blocks, optionally only in `pallets`.
+
+        Keys of the pallets are listed at both blocks and their raw values are read with
+        `state_queryStorage` over the block range, so unchanged values are fetched once and
+        only changed keys are decoded. Changes are reported at `(pallet, item, *keys)` paths,
+        values under the storage keys are compared as a whole, unlike `JsonnetObject.diff`.
+        Key pages and value batches follow `key_page_size` and `request_batch_size` options.
+        Keys, which are not described by the metadata, e.g. pallet storage versions, are skipped.
+        """
+        ...
+
+class AsyncChain:
+    """
+    Same as `Chain`, but every method returns an awaitable, so the event loop is not blocked.
+
+    Awaitables don't run chain queries concurrently: storage is read from the node
+    during Jsonnet evaluation, which is done by a single evaluator thread, so
+    awaitables of every chain are completed one after another.
+    """
+
+    @staticmethod
+    async def connect(
+        url: Optional[str] = None,
+        opts: Optional[ChainOpts] = None,
+        record: Optional[str | PathLike[str]] = None,
+        replay: Optional[str | PathLike[str]] = None,
+    ) -> AsyncChain:
+        """Connect to the node at `url`, `record` and `replay` are the same as for `Chain`"""
+        ...
+
+    async def latest(self) -> BlockState:
+        ...
+
+    async def finalized(self) -> BlockState:
+        """State at the last finalized block"""
+        ...
+
+    async def block(self, block: int) -> BlockState:
+        ...
+
+    async def at_hash(self, hash: bytes | str) -> BlockState:
+        """State at the block with the given hash, which may be outside of the canonical chain"""
         ...
 
-    def block(self, block: int) -> JsonnetObject:
+    async def diff(
+        self, from_block: int, to_block: int, pallets: Optional[Sequence[str]] = None
+    ) -> StateDiff:
+        """Storage changes between two blocks, same as `Chain.diff`"""
         ...
 
-def dump(meta: JsonnetObject | bytes, data: dict[bytes, bytes], opts: Optional[ChainOpts]) -> JsonnetObject:
-    ...
\ No newline at end of file
+def dump(
+    meta: JsonnetObject | bytes,
+    data: Mapping[Any, Any] | Iterable[tuple[Any, Any]],
+    opts: Optional[ChainOpts] = None,
+    children: Optional[Mapping[Any, Mapping[Any, Any]]] = None,
+) -> DumpState:
+    """
+    Decode raw storage with the given metadata.
+
+    `data` is a mapping or an iterable of `(key, value)` pairs. Keys and values
+    may be any bytes-like objects (`bytes`, `memoryview`, numpy arrays, ...),
+    `chainql.hex.HexBytes` or `0x`-prefixed hex strings.
+
+    `children` maps default child trie ids to their storage entries. These are
+    available as `_child` of the result, same as for `BlockState`.
+    """
+    ...
+
+def dump_from_chain_spec(
+    path: str | PathLike[str],
+    meta: JsonnetObject | bytes,
+    opts: Optional[ChainOpts] = None,
+) -> DumpState:
+    """
+    Same as `dump`, but storage is read from `genesis.raw` of the raw chain spec.
+
+    Metadata should be passed explicitly, because it can only be obtained by executing the runtime code.
+    """
+    ...
+
+def dump_from_snapshot(
+    path: str | PathLike[str],
+    meta: JsonnetObject | bytes,
+    opts: Optional[ChainOpts] = None,
+) -> DumpState:
+    """
+    Same as `dump`, but storage is read from the try-runtime `.snap` file.
+
+    Snapshot versions 3 and 4 are supported, other versions raise `ValueError`.
+    Metadata should be passed explicitly, because it can only be obtained by executing the runtime code.
+    """
+    ...
+
+def evaluate(
+    code: str | PathLike[str],
+    ext_vars: Optional[dict[str, Any]] = None,
+    tla: Optional[dict[str, Any]] = None,
+    import_paths: Optional[list[str | PathLike[str]]] = None,
+) -> Any:
+    """
+    Evaluate jsonnet code snippet or file with the chainql standard library.
+
+    `cql` object is available the same way as in the chainql CLI, as a global and as `std.extVar('cql')`.
+    Files are imported, so their relative imports are resolved from their directory.
+    """
+    ...
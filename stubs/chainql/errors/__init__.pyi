@@ -0,0 +1,53 @@
+# Copyright 2024 Valery Klachkov
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import builtins
+from typing import Optional
+
+class ChainqlError(builtins.RuntimeError):
+    """Base class for chainql errors"""
+
+    message: str
+
+    trace: list[str]
+    """Jsonnet stack trace frames with their source locations, innermost first"""
+
+    path: Optional[tuple[str | int, ...]]
+    """Keys and indices of the value, which failed to evaluate, e.g. pallet, storage entry and key"""
+
+class ConnectionError(ChainqlError):
+    """Connection to the node failed"""
+
+class RpcError(ChainqlError):
+    """Node returned an error for the RPC request"""
+
+    code: Optional[int]
+    """JSON-RPC error code, if known"""
+
+class MetadataDecodeError(ChainqlError):
+    """Runtime metadata can not be decoded"""
+
+class StorageDecodeError(ChainqlError):
+    """Storage key or value can not be decoded"""
+
+    key: Optional[str]
+    """Hex encoded storage key, if known"""
+
+class EvaluationError(ChainqlError):
+    """Jsonnet evaluation failed"""
+
+class Cancelled(builtins.KeyboardInterrupt):
+    """Evaluation was cancelled by Ctrl-C"""
+
+    message: str
@@ -19,3 +19,16 @@ def from_hex(data: str) -> bytes:
 def to_hex(data: str) -> bytes:
     """Convert a hex string to bytes"""
     ...
+
+class HexBytes:
+    """Bytes decoded from the chain, shown as `0x`-prefixed hex string"""
+
+    def __init__(self, data: bytes | str) -> None: ...
+    def __bytes__(self) -> bytes: ...
+    def __len__(self) -> int: ...
+    def __eq__(self, other: object) -> bool: ...
+    def __hash__(self) -> int: ...
+
+    def hex(self) -> str:
+        """Hex representation without `0x` prefix, same as `bytes.hex()`"""
+        ...
@@ -0,0 +1,124 @@
+# Copyright 2024 Valery Klachkov
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import inspect
+
+import pytest
+
+import chainql
+from chainql import JsonnetObject
+
+
+def test_tla_callable():
+    out = chainql.evaluate("function(f) std.filter(f, [1, 2, 3, 4])", tla={"f": lambda x: x % 2 == 0})
+
+    assert out == [2, 4]
+
+
+def test_ext_var_callable():
+    out = chainql.evaluate("std.map(std.extVar('f'), ['a', 'b'])", ext_vars={"f": str.upper})
+
+    assert out == ["A", "B"]
+
+
+def test_bound_method():
+    class Counter:
+        def __init__(self):
+            self.calls = []
+
+        def add(self, value):
+            self.calls.append(value)
+            return len(self.calls)
+
+    counter = Counter()
+    out = chainql.evaluate("function(f) [f('a'), f('b')]", tla={"f": counter.add})
+
+    assert out == [1, 2]
+    assert counter.calls == ["a", "b"]
+
+
+def test_callable_argument():
+    apply = chainql.evaluate("function(f, x) f(x)")
+
+    assert apply(lambda x: x + 1, 1) == 2
+
+
+def test_callable_in_data():
+    obj = JsonnetObject.from_python({"f": lambda: "called"})
+
+    assert chainql.evaluate("function(obj) obj.f()", tla={"obj": obj}) == "called"
+
+
+def test_default_arguments():
+    def greet(name, greeting="hello"):
+        return f"{greeting} {name}"
+
+    out = chainql.evaluate(
+        "function(f) [f('a'), f('b', 'hi'), f(name='c', greeting='hey')]",
+        tla={"f": greet},
+    )
+
+    assert out == ["hello a", "hi b", "hey c"]
+
+
+def test_exception():
+    def fail(x):
+        raise KeyError(x)
+
+    with pytest.raises(chainql.errors.EvaluationError) as err:
+        chainql.evaluate("function(f) f('key')", tla={"f": fail})
+
+    assert "python function fail failed" in str(err.value)
+    assert isinstance(err.value.__cause__, KeyError)
+
+
+def test_named_arguments():
+    add = chainql.evaluate("function(a, b=2) a + b")
+
+    assert add(1) == 3
+    assert add(1, b=5) == 6
+    assert add(b=1, a=1) == 2
+
+
+def test_builtin_named_arguments():
+    sort = chainql.evaluate("std.sort")
+
+    assert sort(["bb", "a", "ccc"]) == ["a", "bb", "ccc"]
+    assert sort(["bb", "a", "ccc"], keyF=lambda s: -len(s)) == ["ccc", "bb", "a"]
+
+
+@pytest.mark.parametrize(
+    "call",
+    [lambda f: f(), lambda f: f(1, c=3), lambda f: f(1, a=1)],
+)
+def test_invalid_arguments(call):
+    add = chainql.evaluate("function(a, b=2) a + b")
+
+    with pytest.raises(chainql.errors.EvaluationError):
+        call(add)
+
+
+def test_signature():
+    func = chainql.evaluate("function(a, b=a, c) [a, b, c]")
+
+    params = list(inspect.signature(func).parameters.values())
+
+    assert [p.name for p in params] == ["a", "b", "c"]
+    assert [p.kind for p in params] == [
+        inspect.Parameter.POSITIONAL_OR_KEYWORD,
+        inspect.Parameter.KEYWORD_ONLY,
+        inspect.Parameter.KEYWORD_ONLY,
+    ]
+    assert [p.default for p in params] == [inspect.Parameter.empty, ..., inspect.Parameter.empty]
+    assert func(1, c=3) == [1, 1, 3]
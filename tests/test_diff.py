@@ -0,0 +1,64 @@
+# Copyright 2024 Valery Klachkov
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+from chainql import JsonnetObject
+
+OLD = {
+    "System": {"Number": 1, "Events": ["a"], "ParentHash": "0x00"},
+    "Balances": {"TotalIssuance": 100, "Account": {"0x01": {"free": 10}, "0x02": {"free": 20}}},
+    "Sudo": {"Key": "0x03"},
+}
+
+NEW = {
+    "System": {"Number": 2, "Events": ["a", "b"], "ParentHash": "0x00"},
+    "Balances": {"TotalIssuance": 100, "Account": {"0x01": {"free": 15}, "0x04": {"free": 40}}},
+    "Timestamp": {"Now": 1000},
+}
+
+
+def test_diff():
+    diff = JsonnetObject.from_python(OLD).diff(JsonnetObject.from_python(NEW))
+
+    assert set(diff) == {"added", "removed", "changed"}
+    assert diff["added"] == {
+        ("Balances", "Account", "0x04"): {"free": 40},
+        ("Timestamp",): {"Now": 1000},
+    }
+    assert diff["removed"] == {
+        ("Balances", "Account", "0x02"): {"free": 20},
+        ("Sudo",): {"Key": "0x03"},
+    }
+    assert diff["changed"] == {
+        ("System", "Number"): (1, 2),
+        ("System", "Events"): (["a"], ["a", "b"]),
+        ("Balances", "Account", "0x01", "free"): (10, 15),
+    }
+
+
+def test_reversed_diff():
+    old, new = JsonnetObject.from_python(OLD), JsonnetObject.from_python(NEW)
+
+    diff, reversed_diff = old.diff(new), new.diff(old)
+
+    assert reversed_diff["added"] == diff["removed"]
+    assert reversed_diff["removed"] == diff["added"]
+    assert reversed_diff["changed"] == {
+        path: (after, before) for path, (before, after) in diff["changed"].items()
+    }
+
+
+def test_no_changes():
+    obj = JsonnetObject.from_python(OLD)
+
+    assert obj.diff(JsonnetObject.from_python(OLD)) == {"added": {}, "removed": {}, "changed": {}}
@@ -0,0 +1,78 @@
+# Copyright 2024 Valery Klachkov
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+from array import array
+
+import pytest
+
+import chainql
+
+# Decoding requires real runtime metadata, so storage entries are only checked
+# to be accepted: bogus metadata fails after the storage has been converted.
+INVALID_META = b"\x00"
+
+
+@pytest.mark.parametrize(
+    "data",
+    [
+        {b"\x01": b"\x02"},
+        {"0x01": "0x02"},
+        {chainql.hex.HexBytes("0x01"): chainql.hex.HexBytes("0x02")},
+        [(bytearray(b"\x01"), memoryview(b"\x02"))],
+        iter([(array("B", [1]), b"\x02")]),
+        ((bytes([key]), b"\x02") for key in range(3)),
+    ],
+)
+def test_buffers(data):
+    with pytest.raises(chainql.errors.MetadataDecodeError):
+        chainql.dump(INVALID_META, data)
+
+
+def test_children():
+    with pytest.raises(chainql.errors.MetadataDecodeError):
+        chainql.dump(INVALID_META, {}, children={b"child": [(memoryview(b"\x01"), "0x02")]})
+
+
+def test_invalid_data():
+    with pytest.raises(TypeError, match="data should be a mapping or an iterable of pairs"):
+        chainql.dump(INVALID_META, 1)
+
+
+def test_invalid_entry():
+    with pytest.raises(TypeError, match=r"entry #1 should be a \(key, value\) pair"):
+        chainql.dump(INVALID_META, [(b"\x01", b"\x02"), b"\x03"])
+
+
+def test_invalid_key():
+    with pytest.raises(TypeError, match="invalid storage key 1 at entry #1") as err:
+        chainql.dump(INVALID_META, {b"\x01": b"\x02", 1: b"\x02"})
+
+    assert isinstance(err.value.__cause__, TypeError)
+
+
+def test_invalid_hex_key():
+    with pytest.raises(TypeError, match="invalid storage key '0xzz' at entry #0") as err:
+        chainql.dump(INVALID_META, {"0xzz": b"\x02"})
+
+    assert isinstance(err.value.__cause__, ValueError)
+
+
+def test_invalid_value():
+    with pytest.raises(TypeError, match="invalid value of storage key 0x0102"):
+        chainql.dump(INVALID_META, [(b"\x01\x02", None)])
+
+
+def test_invalid_child():
+    with pytest.raises(TypeError, match="invalid storage of the child trie 0x6368696c64"):
+        chainql.dump(INVALID_META, {}, children={b"child": {b"\x01": 1}})
@@ -0,0 +1,66 @@
+# Copyright 2024 Valery Klachkov
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import pytest
+
+import chainql
+from chainql import JsonnetArray, JsonnetFunc, JsonnetObject
+
+
+def test_object_equality():
+    obj = JsonnetObject.from_python({"a": 1, "b": ["c", {"d": None}]})
+
+    assert obj == JsonnetObject.from_python({"b": ["c", {"d": None}], "a": 1})
+    assert obj == {"a": 1, "b": ["c", {"d": None}]}
+    assert obj != JsonnetObject.from_python({"a": 2, "b": ["c", {"d": None}]})
+    assert obj != {"a": 1}
+    assert obj != object()
+
+
+def test_array_equality():
+    arr = JsonnetArray.from_python([1, "a", {"b": True}])
+
+    assert arr == JsonnetArray.from_python([1, "a", {"b": True}])
+    assert arr == [1, "a", {"b": True}]
+    assert arr != [1, "a"]
+    assert arr != JsonnetArray.from_python([{"b": True}, "a", 1])
+
+
+@pytest.mark.parametrize(
+    "value",
+    [JsonnetObject.from_python({"a": 1}), JsonnetArray.from_python([1])],
+)
+def test_not_hashable(value):
+    with pytest.raises(TypeError):
+        hash(value)
+
+
+def test_func_equality():
+    func, same, other = chainql.evaluate("local f = function(x) x; [f, f, function(x) x]")
+
+    assert isinstance(func, JsonnetFunc)
+    assert func == same
+    assert hash(func) == hash(same)
+    assert func != other
+    assert len({func, same, other}) == 2
+
+
+def test_repr():
+    obj = JsonnetObject.from_python({"a": True, "b": None})
+    arr = JsonnetArray.from_python(["a", True, None, ["b"], {"c": "d"}, "e"])
+    func = chainql.evaluate("function(x, y=1) x")
+
+    assert repr(obj) == "JsonnetObject(keys=['a', 'b'], len=2)"
+    assert repr(arr) == "JsonnetArray(['a', True, None, [...], {...}, ...], len=6)"
+    assert repr(func) == "JsonnetFunc(x, y=...)"
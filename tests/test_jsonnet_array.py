@@ -0,0 +1,91 @@
+# Copyright 2024 Valery Klachkov
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import pytest
+
+from chainql import JsonnetArray
+
+VALUES = [0, 1, 2, 3, 4, 5]
+
+
+@pytest.fixture
+def arr():
+    return JsonnetArray.from_python(VALUES)
+
+
+@pytest.mark.parametrize("index", range(-len(VALUES), len(VALUES)))
+def test_index(arr, index):
+    assert arr[index] == VALUES[index]
+
+
+@pytest.mark.parametrize("index", [len(VALUES), -len(VALUES) - 1])
+def test_index_out_of_range(arr, index):
+    with pytest.raises(IndexError):
+        arr[index]
+
+
+def test_index_type(arr):
+    with pytest.raises(TypeError):
+        arr["0"]
+
+
+@pytest.mark.parametrize(
+    "index",
+    [
+        slice(None),
+        slice(1, 4),
+        slice(-3, None),
+        slice(None, -2),
+        slice(None, None, 2),
+        slice(1, None, 3),
+        slice(None, None, -1),
+        slice(4, 1, -1),
+        slice(-1, -5, -2),
+        slice(0, 3, -1),
+        slice(4, 2),
+        slice(-100, 100),
+    ],
+)
+def test_slice(arr, index):
+    sliced = arr[index]
+
+    assert isinstance(sliced, JsonnetArray)
+    assert len(sliced) == len(VALUES[index])
+    assert sliced.to_python() == VALUES[index]
+
+
+def test_slice_zero_step(arr):
+    with pytest.raises(ValueError):
+        arr[::0]
+
+
+def test_nested_slice(arr):
+    assert arr[1:][::-1][1:3].to_python() == VALUES[1:][::-1][1:3]
+
+
+def test_reversed(arr):
+    assert list(reversed(arr)) == VALUES[::-1]
+    assert list(reversed(arr[1:4])) == VALUES[1:4][::-1]
+
+
+def test_index_and_count():
+    arr = JsonnetArray.from_python(["a", "b", "a", {"c": 1}])
+
+    assert arr.index("a") == 0
+    assert arr.index("a", 1) == 2
+    assert arr.index({"c": 1}) == 3
+    assert arr.count("a") == 2
+
+    with pytest.raises(ValueError):
+        arr.index("a", 3)
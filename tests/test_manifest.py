@@ -0,0 +1,137 @@
+# Copyright 2024 Valery Klachkov
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import io
+import json
+
+import pytest
+
+from chainql import JsonnetArray, JsonnetObject
+
+DATA = {"b": 1, "a": {"d": [True, None], "c": "x"}}
+
+
+@pytest.fixture
+def obj():
+    return JsonnetObject.from_python(DATA)
+
+
+def test_json(obj):
+    assert obj.manifest() == obj.manifest_json()
+    assert obj.manifest("json", indent=0) == obj.manifest_json()
+    assert obj.manifest("json", indent=2) == obj.manifest_json(minified=False)
+
+    assert "\n" not in obj.manifest_json()
+    assert json.loads(obj.manifest_json()) == DATA
+    assert json.loads(obj.manifest("json", indent=4)) == DATA
+
+
+def test_preserve_order(obj):
+    assert list(json.loads(obj.manifest_json())) == ["b", "a"]
+    assert list(json.loads(obj.manifest("json", sort_keys=True))) == ["a", "b"]
+
+    yaml = obj.manifest_yaml()
+    assert yaml.index("b") < yaml.index("a")
+
+    yaml = obj.manifest_yaml(sort_keys=True)
+    assert yaml.index("a") < yaml.index("b")
+
+
+def test_yaml(obj):
+    assert obj.manifest("yaml") == obj.manifest_yaml()
+    assert obj.manifest("yaml", indent=4) == obj.manifest_yaml(indent=4)
+
+    nested = JsonnetObject.from_python({"a": {"b": "c"}})
+    assert nested.manifest_yaml().splitlines()[1].startswith("  ")
+    assert not nested.manifest_yaml().splitlines()[1].startswith("   ")
+    assert nested.manifest_yaml(indent=4).splitlines()[1].startswith("    ")
+
+
+def test_toml():
+    tomllib = pytest.importorskip("tomllib")
+
+    data = {"b": 1, "a": {"d": [True, False], "c": "x"}}
+    obj = JsonnetObject.from_python(data)
+
+    assert obj.manifest("toml") == obj.manifest_toml()
+    assert tomllib.loads(obj.manifest_toml()) == data
+    assert tomllib.loads(obj.manifest_toml(indent=4, sort_keys=True)) == data
+
+
+def test_ini():
+    obj = JsonnetObject.from_python({"main": {"a": "1"}, "sections": {"s": {"b": "c"}}})
+
+    lines = obj.manifest_ini().splitlines()
+    assert obj.manifest("ini") == obj.manifest_ini()
+    assert lines.index("a = 1") < lines.index("[s]") < lines.index("b = c")
+
+
+def test_array():
+    arr = JsonnetArray.from_python([{"b": 1, "a": 2}, "c"])
+
+    assert arr.manifest() == arr.manifest_json()
+    assert json.loads(arr.manifest_json()) == [{"b": 1, "a": 2}, "c"]
+    assert list(json.loads(arr.manifest_json(minified=False))[0]) == ["b", "a"]
+    assert arr.manifest("yaml") == arr.manifest_yaml()
+
+
+def test_unknown_format(obj):
+    with pytest.raises(ValueError, match="unknown manifest format"):
+        obj.manifest("xml")
+
+
+def test_json_to_path(obj, tmp_path):
+    path = tmp_path / "out.json"
+
+    obj.manifest_json_to(path)
+    assert path.read_text() == obj.manifest_json()
+
+    obj.manifest_json_to(str(path), minified=False)
+    assert json.loads(path.read_text()) == DATA
+
+
+@pytest.mark.parametrize("file", [io.StringIO, io.BytesIO])
+def test_json_to_file(obj, file):
+    out = file()
+
+    obj.manifest_json_to(out, sort_keys=True)
+    assert json.loads(out.getvalue()) == DATA
+    assert list(json.loads(out.getvalue())) == ["a", "b"]
+
+
+def test_ndjson(obj):
+    out = io.StringIO()
+
+    obj.manifest_json_to(out, ndjson=True)
+    assert [json.loads(line) for line in out.getvalue().splitlines()] == [
+        {"key": "b", "value": 1},
+        {"key": "a", "value": {"d": [True, None], "c": "x"}},
+    ]
+
+
+def test_ndjson_array():
+    arr = JsonnetArray.from_python([{"a": 1}, "b", None])
+    out = io.StringIO()
+
+    arr.manifest_json_to(out, ndjson=True)
+    assert out.getvalue() == '{"a":1}\n"b"\nnull\n'
+
+    out = io.StringIO()
+    arr.manifest_json_to(out)
+    assert out.getvalue() == arr.manifest_json()
+
+
+def test_json_to_invalid_target(obj):
+    with pytest.raises(ValueError, match="target should be a path or a file object"):
+        obj.manifest_json_to(1)
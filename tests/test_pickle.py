@@ -0,0 +1,91 @@
+# Copyright 2024 Valery Klachkov
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import pickle
+
+import pytest
+
+import chainql
+from chainql import BlockRef, ChainOpts, DumpState, JsonnetArray, JsonnetObject
+
+
+@pytest.mark.parametrize(
+    "cls",
+    [
+        chainql.AsyncChain,
+        chainql.BlockRef,
+        chainql.BlockState,
+        chainql.Chain,
+        chainql.ChainOpts,
+        chainql.ChildTrie,
+        chainql.ChildTries,
+        chainql.DumpState,
+        chainql.JsonnetArray,
+        chainql.JsonnetFunc,
+        chainql.JsonnetObject,
+    ],
+)
+def test_module(cls):
+    assert cls.__module__ == "chainql"
+    assert getattr(chainql, cls.__qualname__) is cls
+
+
+def roundtrip(value):
+    return pickle.loads(pickle.dumps(value))
+
+
+def test_jsonnet_object():
+    obj = JsonnetObject.from_python({"a": 1, "b": [True, None, "c"], "d": {"e": 2.5}})
+
+    restored = roundtrip(obj)
+
+    assert isinstance(restored, JsonnetObject)
+    assert restored == obj
+    assert restored.to_python() == {"a": 1, "b": [True, None, "c"], "d": {"e": 2.5}}
+
+
+def test_jsonnet_array():
+    arr = JsonnetArray.from_python([1, "a", {"b": [2]}])
+
+    restored = roundtrip(arr)
+
+    assert isinstance(restored, JsonnetArray)
+    assert restored.to_python() == [1, "a", {"b": [2]}]
+
+
+def test_chain_opts():
+    opts = ChainOpts(omit_empty=True, key_page_size=100, preload=True)
+
+    restored = roundtrip(opts)
+
+    assert restored.omit_empty
+    assert not restored.include_defaults
+    assert restored.key_page_size == 100
+    assert restored.preload
+
+
+def test_block_ref():
+    block = BlockRef(1, "0x01", "0x00", "0x02", timestamp=1000)
+
+    assert roundtrip(block) == block
+
+
+def test_dump_state():
+    state = DumpState._restore({"System": {"Number": 1}}, {b"child": {b"\x01": b"\x02"}})
+
+    restored = roundtrip(state)
+
+    assert isinstance(restored, DumpState)
+    assert restored.to_python() == {"System": {"Number": 1}}
+    assert bytes(restored._child[b"child"][b"\x01"]) == b"\x02"